@@ -3,6 +3,7 @@ pub const STACK_SIZE: usize = 0x2000;
 pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 pub const KERNEL_HEAP_SIZE: usize = 0x100_0000;
+pub const USER_STACK_SIZE: usize = 0x2000;
 
 pub const MEMORY_END: usize = 0x88000000;
 
@@ -10,3 +11,40 @@ pub const SP: usize = 0;
 pub const STACK_START: usize = usize::MAX - STACK_SIZE + 1;
 pub const TRAMPOLINE: usize = STACK_START - PAGE_SIZE;
 
+/// 支持的最多的核数
+pub const MAX_HART_NUM: usize = 4;
+/// 每个核的 vDSO 槽的大小
+pub const VDSO_SIZE: usize = 4 * PAGE_SIZE;
+
+/// 用户进程与内核共享的数据页，位于所有核的异界传送门之下
+pub const SHARED_PAGE: usize = TRAMPOLINE - MAX_HART_NUM * PAGE_SIZE;
+/// vDSO 槽的起始地址
+pub const VDSO_BASE: usize = SHARED_PAGE - MAX_HART_NUM * VDSO_SIZE;
+/// vDSO 中的协程执行器所在的用户内存
+pub const EXECUTOR_SIZE: usize = PAGE_SIZE;
+pub const EXECUTOR_BASE: usize = VDSO_BASE - EXECUTOR_SIZE;
+/// 每个进程最多的用户态线程数量，包括主线程
+pub const MAX_THREADS: usize = 8;
+/// 用户态线程的栈，主线程使用用户栈，其他线程的栈依次排列
+pub const THREAD_STACK_SIZE: usize = 0x4000;
+pub const THREAD_STACK_BASE: usize = EXECUTOR_BASE - (MAX_THREADS - 1) * THREAD_STACK_SIZE;
+
+/// 用户程序可以自由映射的地址空间的上界，即 Sv39 低半部分的末尾，之上的高位地址由内核管理
+pub const USER_SPACE_END: usize = 1 << 38;
+/// 没有指定地址的匿名映射从这里开始查找空闲的地址
+pub const MMAP_BASE: usize = 0x10_0000_0000;
+
+/// 第 `hartid` 个核的异界传送门，0 号核的就是 `TRAMPOLINE`
+pub const fn portal_va(hartid: usize) -> usize {
+    TRAMPOLINE - hartid * PAGE_SIZE
+}
+
+/// 第 `hartid` 个核的 vDSO 槽
+pub const fn vdso_va(hartid: usize) -> usize {
+    VDSO_BASE + hartid * VDSO_SIZE
+}
+
+/// qemu virt 平台的时钟频率
+pub const CLOCK_FREQ: usize = 12_500_000;
+/// 进程每次被调度后最多运行的时间
+pub const TIME_SLICE: usize = CLOCK_FREQ / 100;
@@ -1,5 +1,10 @@
 use core::alloc::Layout;
 
+use config::PAGE_SIZE_BITS;
+use riscv::register::{scause, sepc, stval};
+
+use crate::{fast_handler, preempt_check, FastResult};
+
 /// 上下文
 #[repr(C)]
 #[allow(missing_docs)]
@@ -32,18 +37,28 @@ pub unsafe extern "C" fn skip_context() {
 }
 
 /// 陷入处理函数
+///
+/// 先保存调用者保存的寄存器，在用户进程的地址空间中调用快速路径 [`fast_handler`]，
+/// 根据返回的 [`FastResult`] 直接返回用户态，或者保存剩余的寄存器之后切换到内核进程，
+/// 此时 a0 寄存器中保存着快速路径的处理结果，作为内核进程的参数。
+/// 快速路径运行在上下文下方的进程栈上，用户栈可能还没有分配。
 #[naked]
 #[link_section = ".text.trampoline"]
 pub unsafe extern "C" fn trap_entry() {
     core::arch::asm!(
         ".align 2",
-        // 保存进程上下文
+        // 从 S 态陷入时，陷入地址在异界传送门中说明快速路径访问了非法的用户内存，直接杀死进程；
+        // 否则是内核自身的错误，交给 `kernel_fault`
         "
-            sd sp, -1*8(x0)
-            sd ra, -2*8(x0) 
-            sd tp, -4*8(x0)
-            sd gp, -5*8(x0)
             sd t0, -32*8(x0)
+            csrr t0, sstatus
+            andi t0, t0, {spp}
+            bnez t0, 2f
+        ",
+        // 保存调用者保存的寄存器
+        "
+            sd sp, -1*8(x0)
+            sd ra, -2*8(x0)
             sd t1, -31*8(x0)
             sd t2, -30*8(x0)
             sd t3, -29*8(x0)
@@ -58,6 +73,20 @@ pub unsafe extern "C" fn trap_entry() {
             sd a5, -20*8(x0)
             sd a6, -19*8(x0)
             sd a7, -18*8(x0)
+            csrr t1, sepc
+            sd t1, -3*8(x0)
+        ",
+        // 调用快速路径，使用上下文下方的进程栈
+        "
+            li a0, {ctx}
+            andi sp, a0, -16
+            call {fast_handler}
+            beqz a0, 1f
+        ",
+        // 完整陷入，保存剩余的寄存器
+        "
+            sd tp, -4*8(x0)
+            sd gp, -5*8(x0)
             sd s0, -17*8(x0)
             sd s1, -16*8(x0)
             sd s2, -15*8(x0)
@@ -70,29 +99,86 @@ pub unsafe extern "C" fn trap_entry() {
             sd s9, -8*8(x0)
             sd s10, -7*8(x0)
             sd s11, -6*8(x0)
-            csrr t1 , sepc
-            sd t1, -3*8(x0)
             csrr t0, satp
             sd t0, -33*8(x0)
+            j 3f
+        2:
+            csrr t0, sepc
+            csrr a0, stvec
+            xor  t0, t0, a0
+            srli t0, t0, {page_bits}
+            bnez t0, 4f
+            li a0, {kill}
         ",
         // 切换地址空间
         "
+        3:
             csrr t0, sscratch
             csrw satp, t0
             sfence.vma
         ",
-        // 恢复内核上下文
+        // 恢复内核上下文，内核的 tp 寄存器保存着核的编号
         "
             ld sp, -1*8(x0)
-            ld ra, -2*8(x0) 
+            ld ra, -2*8(x0)
+            ld tp, -4*8(x0)
             ret
         ",
+        // 快速路径处理完成，恢复调用者保存的寄存器直接返回用户态
+        "
+        1:
+            ld t1, -3*8(x0)
+            csrw sepc, t1
+            ld sp, -1*8(x0)
+            ld ra, -2*8(x0)
+            ld t0, -32*8(x0)
+            ld t1, -31*8(x0)
+            ld t2, -30*8(x0)
+            ld t3, -29*8(x0)
+            ld t4, -28*8(x0)
+            ld t5, -27*8(x0)
+            ld t6, -26*8(x0)
+            ld a0, -25*8(x0)
+            ld a1, -24*8(x0)
+            ld a2, -23*8(x0)
+            ld a3, -22*8(x0)
+            ld a4, -21*8(x0)
+            ld a5, -20*8(x0)
+            ld a6, -19*8(x0)
+            ld a7, -18*8(x0)
+            sret
+        ",
+        // 跳板页映射在异界传送门，只能通过绝对地址跳转到 `.text` 中的函数
+        "
+        4:
+            ld t0, 5f
+            jr t0
+            .align 3
+        5:
+            .dword {kernel_fault}
+        ",
+        spp          = const 1 << 8,
+        page_bits    = const PAGE_SIZE_BITS,
+        ctx          = const -(core::mem::size_of::<FlowContext>() as isize),
+        kill         = const FastResult::Kill as usize,
+        fast_handler = sym fast_handler,
+        kernel_fault = sym kernel_fault,
         options(noreturn)
     )
 }
 
+/// 内核自身的陷入，说明内核出现了错误
+extern "C" fn kernel_fault() -> ! {
+    panic!(
+        "unexpected trap in kernel: {:?}, sepc = {:#x}, stval = {:#x}",
+        scause::read().cause(),
+        sepc::read(),
+        stval::read()
+    )
+}
+
 /// 内核切换到用户程序地址空间，默认 a0 寄存器中保存着目标进程的地址空间
-/// 先切换地址空间，之后直接从高位虚拟地址恢复上下文
+/// 先切换地址空间，检查协程抢占之后直接从高位虚拟地址恢复上下文
 #[naked]
 #[link_section = ".text.trampoline"]
 pub unsafe extern "C" fn restore() {
@@ -103,6 +189,12 @@ pub unsafe extern "C" fn restore() {
             csrw satp, a0
             sfence.vma
         ",
+        // 检查是否需要抢占协程，同样使用上下文下方的进程栈
+        "
+            li a0, {ctx}
+            andi sp, a0, -16
+            call {preempt_check}
+        ",
         "
             ld t1, -3*8(x0)
             csrw sepc, t1
@@ -139,6 +231,8 @@ pub unsafe extern "C" fn restore() {
             ld s11, -6*8(x0)
             sret
         ",
+        ctx           = const -(core::mem::size_of::<FlowContext>() as isize),
+        preempt_check = sym preempt_check,
         options(noreturn)
     )
 }
\ No newline at end of file
@@ -1,7 +1,11 @@
+use core::ptr::addr_of;
+use crate::FlowContext;
+use syscall::SyscallId;
+use vdso::SharedData;
 
 /// 快速路径处理结果。
-#[allow(unused)]
 #[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FastResult {
     /// 处理完成，直接返回
     Restore = 0,
@@ -9,4 +13,116 @@ pub enum FastResult {
     Continue = 1,
     /// 恶意操作，进入内核直接杀死进程
     Kill = 2,
-}
\ No newline at end of file
+}
+
+/// 来自 U 态的系统调用
+const USER_ENV_CALL: usize = 8;
+
+/// 上下文的字数
+const WORDS: usize = core::mem::size_of::<FlowContext>() / 8;
+
+// 快速路径位于跳板页中，调试模式下 `read_volatile`、`AtomicUsize::load` 等函数不会被内联，
+// 只能通过内联汇编访问内存
+
+/// 读取 `addr` 处的一个字
+#[inline(always)]
+unsafe fn load(addr: usize) -> usize {
+    let value: usize;
+    core::arch::asm!("ld {}, 0({})", out(reg) value, in(reg) addr, options(nostack, readonly));
+    value
+}
+
+/// 以 acquire 语义读取 `addr` 处的一个字
+#[inline(always)]
+unsafe fn load_acquire(addr: usize) -> usize {
+    let value: usize;
+    core::arch::asm!("ld {}, 0({})", "fence r, rw", out(reg) value, in(reg) addr, options(nostack));
+    value
+}
+
+/// 向 `addr` 处写入一个字
+#[inline(always)]
+unsafe fn store(addr: usize, value: usize) {
+    core::arch::asm!("sd {}, 0({})", in(reg) value, in(reg) addr, options(nostack));
+}
+
+/// 复制 `src` 处第 `from` 个字开始的上下文到 `dst`
+#[inline(always)]
+unsafe fn copy_context(dst: usize, src: usize, from: usize) {
+    let mut i = from;
+    while i < WORDS {
+        let offset = i.wrapping_mul(8);
+        store(dst.wrapping_add(offset), load(src.wrapping_add(offset)));
+        i += 1;
+    }
+}
+
+/// 快速路径处理函数
+///
+/// 在 `trap_entry` 保存完调用者保存寄存器之后调用，此时仍处于用户进程的地址空间，
+/// 使用上下文下方的进程栈。这个函数以及它调用的所有函数都必须位于跳板页内，
+/// 因此只能直接读写 csr，不能调用 `.text` 段中的函数。
+#[link_section = ".text.trampoline"]
+pub extern "C" fn fast_handler(ctx: &mut FlowContext) -> FastResult {
+    let scause: usize;
+    unsafe { core::arch::asm!("csrr {}, scause", out(reg) scause) };
+    // 抢占时要在用户栈上保存上下文，用户栈可能还没有分配，只在从内核返回时检查
+    match scause {
+        USER_ENV_CALL => fast_syscall(ctx),
+        // 其余的异常需要完整陷入，时钟中断说明时间片到期，需要内核重新调度
+        _ => FastResult::Continue,
+    }
+}
+
+/// 返回用户态之前检查内核是否通过共享页唤醒了协程
+///
+/// 有的话把上下文保存到用户栈上，转而执行 vDSO 中的抢占入口，参数为保存的上下文。
+/// 快速路径中没有保存被调用者保存的寄存器，由抢占入口补全。
+/// 在 `restore` 切换到用户地址空间之后调用。
+/// 只有内核提前分配了保存上下文的用户栈、设置了上下文下方的抢占标志时才抢占，
+/// 此后其他核才唤醒的协程留到下一次返回用户态时处理
+#[link_section = ".text.trampoline"]
+pub extern "C" fn preempt_check(ctx: &mut FlowContext) {
+    let ctx_addr = ctx as *mut FlowContext as usize;
+    let armed = ctx_addr.wrapping_sub(8);
+    unsafe {
+        if load(armed) == 0 {
+            return;
+        }
+        store(armed, 0);
+    }
+    let entry = unsafe { load_acquire(addr_of!(SharedData::user().preempt_entry) as usize) };
+    if entry == 0 {
+        return;
+    }
+    let frame = ctx.sp.wrapping_sub(core::mem::size_of::<FlowContext>()) & !15;
+    // 不能调用 `.text` 中的 memcpy
+    unsafe { copy_context(frame, ctx_addr, 0) };
+    ctx.sp = frame;
+    ctx.a[0] = frame;
+    ctx.pc = entry;
+}
+
+/// 不需要访问内核数据的系统调用直接在快速路径完成
+#[inline(always)]
+fn fast_syscall(ctx: &mut FlowContext) -> FastResult {
+    const GET_TIME: usize = SyscallId::get_time as usize;
+    const PREEMPT_RETURN: usize = SyscallId::preempt_return as usize;
+    match ctx.a[7] {
+        GET_TIME => {
+            let time: usize;
+            unsafe { core::arch::asm!("csrr {}, time", out(reg) time) };
+            ctx.a[0] = time;
+            ctx.pc = ctx.pc.wrapping_add(4);
+            FastResult::Restore
+        }
+        // 从 vDSO 的抢占入口保存的上下文恢复，被调用者保存的寄存器已经由 vDSO 恢复
+        PREEMPT_RETURN => {
+            let src = ctx.a[0];
+            // 跳过 satp
+            unsafe { copy_context(ctx as *mut FlowContext as usize, src, 1) };
+            FastResult::Restore
+        }
+        _ => FastResult::Continue,
+    }
+}
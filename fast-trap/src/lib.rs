@@ -10,41 +10,31 @@ mod fast;
 
 mod stack;
 
-use config::TRAMPOLINE;
-pub use stack::{Stack, alloc_stack};
+use config::portal_va;
+pub use stack::{Stack, alloc_stack, dealloc_stack};
 pub use context::{FlowContext, skip_context, trap_entry, restore};
+pub use fast::{FastResult, fast_handler, preempt_check};
 
 
+use linker::locate_trampoline;
 use riscv::register::{
     stvec::TrapMode,
-    stvec, sscratch,
+    stvec, sscratch, sstatus,
 };
-use vmm::KERNEL_SPACE;
+use vmm::kernel_token;
 
-/// 初始化中断模块
-pub fn trap_init() {
+/// 初始化第 `hartid` 个核的中断模块
+pub fn trap_init(hartid: usize) {
     unsafe {
-        stvec::write(TRAMPOLINE, TrapMode::Direct);
-        sscratch::write(KERNEL_SPACE.lock().token());
+        stvec::write(portal_addr(hartid, trap_entry as usize), TrapMode::Direct);
+        sscratch::write(kernel_token(hartid));
+        // 快速路径在 S 态访问用户栈顶的上下文
+        sstatus::set_sum();
     }
 }
 
-// /// 内核处理中断异常函数
-// pub fn trap_handler() {
-//     let scause = scause::read();
-//     let stval = stval::read();
-//     //println!("into {:?}", scause.cause());
-//     match scause.cause() {
-//         Trap::Exception(Exception::UserEnvCall) => {
-//             // jump to next instruction anyway
-//             log::info!("user syscall");
-//         }
-//         _ => {
-//             panic!(
-//                 "Unsupported trap {:?}, stval = {:#x}!",
-//                 scause.cause(),
-//                 stval
-//             );
-//         }
-//     }
-// }
\ No newline at end of file
+/// 跳板页中的函数映射到第 `hartid` 个核的异界传送门之后的地址
+#[inline]
+pub fn portal_addr(hartid: usize, f: usize) -> usize {
+    f - locate_trampoline().start + portal_va(hartid)
+}
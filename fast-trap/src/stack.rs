@@ -1,5 +1,5 @@
 use core::ptr::NonNull;
-use vmm::stack_alloc;
+use vmm::{stack_alloc, stack_dealloc};
 use config::STACK_SIZE;
 
 use crate::FlowContext;
@@ -16,6 +16,15 @@ impl Stack {
 
         unsafe { NonNull::new_unchecked(ctx as *mut usize as *mut FlowContext) }
     }
+
+    /// 抢占标志，位于上下文下方的一个字
+    ///
+    /// 内核为抢占准备好用户栈之后置位，[`preempt_check`](crate::preempt_check) 读取并清除。
+    /// 快速路径的栈指针指向这个字，调用的函数只使用它下方的栈
+    pub fn preempt_flag(&self) -> NonNull<usize> {
+        let flag = self.context().as_ptr() as usize - core::mem::size_of::<usize>();
+        unsafe { NonNull::new_unchecked(flag as *mut usize) }
+    }
 }
 
 /// 分配栈
@@ -24,3 +33,8 @@ pub fn alloc_stack() -> Option<NonNull<Stack>> {
         NonNull::new_unchecked(p as *mut usize as *mut Stack)
     })
 }
+
+/// 回收 [`alloc_stack`] 分配的栈，调用者需要保证栈不再被使用
+pub fn dealloc_stack(stack: NonNull<Stack>) {
+    stack_dealloc(stack.as_ptr() as usize);
+}
@@ -1,11 +1,28 @@
 fn main() {
     use std::{env, fs, path::PathBuf};
 
-    let ld = &PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("linker.ld");
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let ld = &out.join("linker.ld");
     fs::write(ld, linker::SCRIPT).unwrap();
 
+    // 把 APP 指定的用户程序链接进内核，xmas-elf 要求 ELF 数据是对齐的
+    let app = match env::var("APP") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={path}");
+            format!(
+                "#[repr(C, align(4096))]
+                struct Aligned<T: ?Sized>(T);
+                static ALIGNED: &Aligned<[u8]> = &Aligned(*include_bytes!({path:?}));
+                pub static APP: &[u8] = &ALIGNED.0;"
+            )
+        }
+        Err(_) => String::from("pub static APP: &[u8] = &[];"),
+    };
+    fs::write(out.join("app.rs"), app).unwrap();
+
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-env-changed=LOG");
     println!("cargo:rerun-if-env-changed=APP_ASM");
+    println!("cargo:rerun-if-env-changed=APP");
     println!("cargo:rustc-link-arg=-T{}", ld.display());
 }
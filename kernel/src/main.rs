@@ -4,23 +4,30 @@
 #![deny(warnings)]
 
 mod console;
+mod syscall;
+mod timer;
 mod trap;
 
 #[macro_use]
 extern crate rcore_console;
+extern crate alloc;
 
-extern crate syscall;
-
-
+use alloc::boxed::Box;
 use sbi_rt::*;
-use config::STACK_SIZE;
+use config::{STACK_SIZE, MAX_HART_NUM};
 use fast_trap::{Stack, skip_context, FlowContext};
 use trap::kern_process;
-use syscall::*;
 
+// 由 build.rs 根据环境变量 APP 生成
+include!(concat!(env!("OUT_DIR"), "/app.rs"));
 
+
+/// 每个核各自的栈
 #[link_section = ".bss.stack"]
-static mut STACK: Stack = Stack([0; STACK_SIZE]);
+static mut STACKS: [Stack; MAX_HART_NUM] = {
+    const STACK: Stack = Stack([0; STACK_SIZE]);
+    [STACK; MAX_HART_NUM]
+};
 
 /// 设置栈并跳转到 Rust。
 #[naked]
@@ -28,49 +35,102 @@ static mut STACK: Stack = Stack([0; STACK_SIZE]);
 #[link_section = ".text.entry"]
 unsafe extern "C" fn _start() -> ! {
     // 在栈顶已经预留上下文的空间，sscratch 指向上下文的起始地址
-    
     core::arch::asm!(
-        "   la   sp, {stack} + {stack_size}
+        "   call {hart_stack}
             call {skip_context}
             j    {main}
         ",
-        stack_size      = const STACK_SIZE,
-        stack           = sym STACK,
+        hart_stack      = sym hart_stack,
         skip_context    = sym skip_context,
         main            = sym rust_main,
         options(noreturn),
     )
 }
 
+/// 其他核的入口，由启动核通过 HSM 启动
+#[naked]
+unsafe extern "C" fn _secondary_start() -> ! {
+    core::arch::asm!(
+        "   call {hart_stack}
+            call {skip_context}
+            j    {main}
+        ",
+        hart_stack      = sym hart_stack,
+        skip_context    = sym skip_context,
+        main            = sym secondary_main,
+        options(noreturn),
+    )
+}
 
-extern "C" fn rust_main() -> ! {
+/// a0 为核的编号，sp 指向 `STACKS` 中对应的栈的栈顶
+#[naked]
+unsafe extern "C" fn hart_stack() {
+    core::arch::asm!(
+        "   la   sp, {stacks}
+            addi t0, a0, 1
+            li   t1, {stack_size}
+            mul  t0, t0, t1
+            add  sp, sp, t0
+            ret
+        ",
+        stack_size      = const STACK_SIZE,
+        stacks          = sym STACKS,
+        options(noreturn),
+    )
+}
+
+
+extern "C" fn rust_main(hartid: usize) -> ! {
     // 初始化内存布局，bss 段清零
     unsafe { linker::zero_bss(); }
     // 初始化 `console`
     console::init_console();
-    vmm::init();
+    vmm::init(hartid);
     println!("vmm init done");
+    init_hart(hartid);
+    if APP.is_empty() {
+        log::warn!("no user app, set APP to the path of an ELF file");
+        system_reset(Shutdown, NoReason);
+        unreachable!()
+    }
+    task::set_scheduler(Box::new(task::PriorityScheduler::default()));
+    let process = task::Process::from_elf(APP).expect("failed to load the user app");
+    task::add_process(process);
+    // 所有的核共用同一个就绪队列
+    for id in (0..MAX_HART_NUM).filter(|&id| id != hartid) {
+        let ret = hart_start(id, _secondary_start as usize, 0);
+        if ret.error != 0 {
+            log::debug!("hart {} not started, error = {:#x}", id, ret.error);
+        }
+    }
+    trap::schedule()
+}
+
+extern "C" fn secondary_main(hartid: usize) -> ! {
+    vmm::init_hart(hartid);
+    init_hart(hartid);
+    trap::schedule()
+}
+
+/// 初始化当前核的内核上下文、中断以及时钟
+fn init_hart(hartid: usize) {
     let sp = usize::MAX - core::mem::size_of::<FlowContext>() + 1;
     let ra = kern_process as usize;
+    // 内核栈顶的上下文，每次进入内核时从中恢复 sp、ra 和 tp
     unsafe {
         core::arch::asm!(
+            "mv  tp, {hartid}",
             "sd {sp}, -1*8(x0)",
             "sd {ra}, -2*8(x0)",
+            "sd tp, -4*8(x0)",
+            hartid = in(reg) hartid,
             sp = in(reg) sp,
             ra = in(reg) ra,
         );
     }
-    fast_trap::trap_init();
-    log::info!("{:?}", SyscallId::write);
-    read!(1, 2, 3);
-    crate::write!(1);
-    // write("sss");
-    // log::debug!("{}", read!());
-    // log::debug!("{}", crate::write!());
-    // let init_proc = task::Process::new();
-    // init_proc.execute();
-    system_reset(Shutdown, NoReason);
-    unreachable!()
+    fast_trap::trap_init(hartid);
+    timer::init();
+    log::info!("hart {} started", hartid);
 }
 
 
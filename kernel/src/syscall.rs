@@ -0,0 +1,222 @@
+use alloc::boxed::Box;
+use core::future::poll_fn;
+use config::CLOCK_FREQ;
+use riscv::register::time;
+use syscall::{SyscallHandler, UserSlice, UserSliceMut};
+use task::ProcId;
+use vmm::{translated_byte_buffer, translated_refmut, MapPermission, MemorySet};
+use crate::{timer::Sleep, trap::Action};
+
+/// 标准输出
+const STDOUT: usize = 1;
+
+/// 用户程序传入的 `PROT_READ`、`PROT_WRITE`、`PROT_EXEC` 之外的位不合法
+const PROT_MASK: usize = 0b111;
+
+/// 内核处理系统调用时的语境
+pub struct SyscallContext<'a> {
+    /// 发起系统调用的进程的地址空间
+    pub space: &'a mut MemorySet,
+    /// 地址空间的 satp
+    pub satp: usize,
+    /// 系统调用完成之后进程的去向
+    pub action: Action,
+}
+
+impl<'a> SyscallContext<'a> {
+    /// 默认在系统调用完成之后恢复进程
+    pub fn new(space: &'a mut MemorySet) -> Self {
+        let satp = space.token();
+        Self { space, satp, action: Action::Resume }
+    }
+}
+
+/// 把 `PROT_*` 转换成区域的权限，和 `MapPermission` 的 R、W、X 相差一位
+///
+/// 只写的页表项是保留的编码，可写的区域同时可读
+fn prot_to_perm(prot: usize) -> Option<MapPermission> {
+    if prot & !PROT_MASK != 0 {
+        return None;
+    }
+    let mut perm = MapPermission::from_bits((prot << 1) as u8)?;
+    if perm.contains(MapPermission::W) {
+        perm |= MapPermission::R;
+    }
+    Some(perm)
+}
+
+impl SyscallHandler for SyscallContext<'_> {
+    fn read(&mut self, fd: usize, _buffer: UserSliceMut<u8>) -> isize {
+        log::warn!("read from fd {} is not supported", fd);
+        -1
+    }
+
+    fn write(&mut self, fd: usize, buffer: UserSlice<u8>) -> isize {
+        if fd != STDOUT {
+            log::warn!("write to fd {} is not supported", fd);
+            return -1;
+        }
+        // 缓冲区可能还没有分配
+        if self.space.fault_in(buffer.ptr as usize, buffer.len, MapPermission::R).is_err() {
+            return -1;
+        }
+        let buffers = match translated_byte_buffer(self.satp, buffer.ptr, buffer.len) {
+            Ok(buffers) => buffers,
+            Err(_) => return -1,
+        };
+        for bytes in buffers {
+            print!("{}", core::str::from_utf8(bytes).unwrap_or("?"));
+        }
+        buffer.len as isize
+    }
+
+    fn exit(&mut self, exit_code: i32) -> isize {
+        self.action = Action::Exit(exit_code);
+        0
+    }
+
+    fn exit_group(&mut self, exit_code: i32) -> isize {
+        self.exit(exit_code)
+    }
+
+    fn fork(&mut self) -> isize {
+        self.action = Action::Fork;
+        0
+    }
+
+    fn waitpid(&mut self, pid: isize, exit_code: *mut i32) -> isize {
+        let process = task::current().expect("no process is running");
+        let satp = self.satp;
+        let exit_code = exit_code as usize;
+        // 子进程可能还没有结束，在内核协程中等待
+        self.action = Action::Block(Box::pin(poll_fn(move |cx| {
+            process.poll_wait(pid, cx.waker()).map(|child| match child {
+                Some((pid, code)) => {
+                    // 退出码所在的页可能还没有分配或者是写时复制的
+                    let writable = exit_code != 0
+                        && process
+                            .inner
+                            .lock()
+                            .space
+                            .fault_in(exit_code, core::mem::size_of::<i32>(), MapPermission::W)
+                            .is_ok();
+                    if writable {
+                        if let Ok(slot) = translated_refmut(satp, exit_code as *mut i32) {
+                            *slot = code;
+                        }
+                    }
+                    pid.get_usize() as isize
+                }
+                None => -1,
+            })
+        })));
+        0
+    }
+
+    fn getpid(&mut self) -> isize {
+        let process = task::current().expect("no process is running");
+        process.pid.get_usize() as isize
+    }
+
+    fn kill(&mut self, pid: isize) -> isize {
+        if pid < 0 {
+            return -1;
+        }
+        let current = task::current().expect("no process is running");
+        let pid = ProcId::from_usize(pid as usize);
+        if pid == current.pid {
+            // 处理系统调用时持有着当前进程的锁
+            self.action = Action::Kill;
+            return 0;
+        }
+        // 两个进程互相杀死时不能同时持有两个进程的锁
+        match task::find_process(pid) {
+            Some(process) => {
+                self.action = Action::KillOther(process);
+                0
+            }
+            None => -1,
+        }
+    }
+
+    fn brk(&mut self, addr: usize) -> isize {
+        // 和 Linux 一样，失败时返回原来的程序断点
+        let current = self.space.current_brk();
+        if addr == 0 {
+            return current as isize;
+        }
+        self.space.brk(addr).unwrap_or(current) as isize
+    }
+
+    fn sbrk(&mut self, increment: isize) -> isize {
+        match self.space.sbrk(increment) {
+            Ok(old_brk) => old_brk as isize,
+            Err(_) => -1,
+        }
+    }
+
+    fn mmap(&mut self, start: usize, len: usize, prot: usize) -> isize {
+        let perm = match prot_to_perm(prot) {
+            Some(perm) => perm,
+            None => return -1,
+        };
+        match self.space.mmap(start, len, perm) {
+            Ok(start) => start as isize,
+            Err(_) => -1,
+        }
+    }
+
+    fn munmap(&mut self, start: usize, len: usize) -> isize {
+        match self.space.munmap(start, len) {
+            Ok(()) => 0,
+            Err(_) => -1,
+        }
+    }
+
+    fn mprotect(&mut self, start: usize, len: usize, prot: usize) -> isize {
+        match prot_to_perm(prot) {
+            Some(perm) if self.space.mprotect(start, len, perm).is_ok() => 0,
+            _ => -1,
+        }
+    }
+
+    fn sched_yield(&mut self) -> isize {
+        self.action = Action::Yield;
+        0
+    }
+
+    fn sleep(&mut self, ms: usize) -> isize {
+        // 不在内核中忙等，挂起进程直到时间到达
+        // 时间过长时饱和到 usize::MAX，相当于永远不会到达
+        let deadline = time::read().saturating_add(ms.saturating_mul(CLOCK_FREQ) / 1000);
+        self.action = Action::Block(Box::pin(async move {
+            Sleep::new(deadline).await;
+            0
+        }));
+        0
+    }
+
+    fn notify_after(&mut self, ms: usize, coroutine: usize) -> isize {
+        if coroutine >= vdso::MAX_COROUTINES {
+            return -1;
+        }
+        let process = task::current().expect("no process is running");
+        let deadline = time::read().saturating_add(ms.saturating_mul(CLOCK_FREQ) / 1000);
+        task::spawn_notify(process, coroutine, async move {
+            Sleep::new(deadline).await;
+            0
+        });
+        0
+    }
+
+    fn get_time(&mut self) -> isize {
+        // 通常在快速路径中完成
+        time::read() as isize
+    }
+
+    fn preempt_return(&mut self, _frame: usize) -> isize {
+        // 只在快速路径中完成
+        log::warn!("preempt_return should be handled on the fast path");
+        -1
+    }
+}
@@ -0,0 +1,64 @@
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use config::TIME_SLICE;
+use riscv::register::{sie, time};
+use spin::Mutex;
+
+/// 等待时间到达的内核协程
+static SLEEPERS: Mutex<Vec<(usize, Waker)>> = Mutex::new(Vec::new());
+
+/// 开启 S 态时钟中断
+pub fn init() {
+    unsafe { sie::set_stimer() };
+}
+
+/// 从现在开始计时一个时间片，到期之后触发时钟中断
+pub fn set_next_trigger() {
+    sbi_rt::set_timer((time::read() + TIME_SLICE) as u64);
+}
+
+/// 唤醒时间已经到达的内核协程
+pub fn wake_expired() {
+    let now = time::read();
+    SLEEPERS.lock().retain(|(deadline, waker)| {
+        if *deadline <= now {
+            waker.wake_by_ref();
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// 等待到 `deadline` 的 future
+pub struct Sleep {
+    deadline: usize,
+}
+
+impl Sleep {
+    pub fn new(deadline: usize) -> Self {
+        Self { deadline }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if time::read() >= self.deadline {
+            Poll::Ready(())
+        } else {
+            // 重复轮询时只更新已经登记的唤醒器，提前唤醒只会多轮询一次
+            let mut sleepers = SLEEPERS.lock();
+            match sleepers.iter_mut().find(|(_, waker)| waker.will_wake(cx.waker())) {
+                Some(entry) => entry.0 = entry.0.min(self.deadline),
+                None => sleepers.push((self.deadline, cx.waker().clone())),
+            }
+            Poll::Pending
+        }
+    }
+}
@@ -1,12 +1,204 @@
-use fast_trap::FlowContext;
+use alloc::{boxed::Box, sync::Arc};
+use core::{future::Future, pin::Pin};
+use fast_trap::{FlowContext, FastResult};
+use riscv::register::{
+    scause::{self, Exception, Interrupt, Trap},
+    stval,
+};
+use sbi_rt::*;
+use task::{Process, ProcessState};
+use vmm::{translated_ref, MapPermission, MemorySet};
+use crate::{syscall::SyscallContext, timer};
 
+/// 陷入处理之后进程的去向
+pub enum Action {
+    /// 恢复进程继续执行
+    Resume,
+    /// 让出处理器，重新加入就绪队列
+    Yield,
+    /// 复制出子进程，在释放进程的锁之后进行
+    Fork,
+    /// 挂起进程，在内核协程中等待操作完成，结果作为系统调用的返回值
+    Block(Pin<Box<dyn Future<Output = isize> + Send>>),
+    /// 进程主动结束
+    Exit(i32),
+    /// 杀死进程
+    Kill,
+    /// 杀死另一个进程之后继续执行，在释放进程的锁之后进行
+    KillOther(Arc<Process>),
+}
 
 /// 内核进程
-#[allow(unused)]
-pub fn kern_process() {
-    log::info!("into kernel");
-    
-        let ctx = unsafe { *((usize::MAX - core::mem::size_of::<FlowContext>() + 1) as *mut usize as *mut FlowContext) };
-        log::info!("{:#x?}", ctx);
-    
-}
\ No newline at end of file
+///
+/// 由 `trap_entry` 切换地址空间之后进入，参数为快速路径的处理结果
+pub extern "C" fn kern_process(result: FastResult) -> ! {
+    let process = task::current().expect("no process is running");
+    let action = match result {
+        FastResult::Continue => {
+            let mut inner = process.inner.lock();
+            if inner.is_killed() {
+                Action::Kill
+            } else {
+                // 用户态的执行器通过共享页报告优先级
+                inner.sync_priority();
+                let inner = &mut *inner;
+                trap_handler(unsafe { inner.ctx.as_mut() }, &mut inner.space)
+            }
+        }
+        FastResult::Kill => Action::Kill,
+        FastResult::Restore => unreachable!("fast path returns to user directly"),
+    };
+    match action {
+        Action::Resume => process.execute(),
+        Action::Yield => {
+            // 仍然绑定着这个核的槽，在这个核上再次运行时不需要重新绑定
+            process.inner.lock().set_state(ProcessState::Prepared(task::hart_id()));
+            task::add_process(process);
+        }
+        Action::Fork => {
+            // 系统调用已经处理完成，直接修改返回值，内存不足时返回 -1
+            let ret = match process.fork() {
+                Ok(child) => {
+                    let pid = child.pid.get_usize();
+                    task::add_process(child);
+                    pid
+                }
+                Err(err) => {
+                    log::warn!("fork failed: {:?}", err);
+                    usize::MAX
+                }
+            };
+            unsafe { process.inner.lock().ctx.as_mut() }.a[0] = ret;
+            process.execute()
+        }
+        Action::KillOther(target) => {
+            target.kill();
+            drop(target);
+            process.execute()
+        }
+        Action::Block(future) => task::park(process, future),
+        Action::Exit(exit_code) => {
+            process.exit(exit_code);
+            // schedule 不会返回，需要手动释放
+            drop(process);
+        }
+        Action::Kill => {
+            log::warn!("process {:?} killed", process.pid);
+            process.exit(-1);
+            drop(process);
+        }
+    }
+    schedule()
+}
+
+/// 从调度器中取出下一个进程运行
+///
+/// 就绪队列为空时轮询内核协程，等待被挂起的进程或者其他核上的进程，所有进程都结束之后关机
+pub fn schedule() -> ! {
+    task::set_current(None);
+    loop {
+        task::exit_killed();
+        timer::wake_expired();
+        task::run_coroutines();
+        if let Some(next) = task::fetch_process() {
+            // 在就绪队列中被杀死
+            if next.inner.lock().is_killed() {
+                next.exit(-1);
+                continue;
+            }
+            task::set_current(Some(next.clone()));
+            timer::set_next_trigger();
+            next.execute()
+        }
+        if task::process_num() == 0 {
+            log::info!("no process left, shutdown");
+            system_reset(Shutdown, NoReason);
+            unreachable!()
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// 内核处理中断异常函数
+fn trap_handler(ctx: &mut FlowContext, space: &mut MemorySet) -> Action {
+    let satp = space.token();
+    let scause = scause::read();
+    let stval = stval::read();
+    match scause.cause() {
+        Trap::Exception(Exception::UserEnvCall) => {
+            let mut syscall_ctx = SyscallContext::new(space);
+            let ret = syscall::dispatch(&mut syscall_ctx, &ctx.a).unwrap_or_else(|| {
+                log::warn!("unsupported syscall {}", ctx.a[7]);
+                -1
+            });
+            ctx.a[0] = ret as usize;
+            ctx.pc += 4;
+            syscall_ctx.action
+        }
+        Trap::Exception(Exception::Breakpoint) => {
+            log::info!("breakpoint at {:#x}", ctx.pc);
+            // 压缩指令 c.ebreak 只有 2 字节
+            match translated_ref(satp, ctx.pc as *const u16) {
+                Ok(&inst) => {
+                    ctx.pc += if inst & 0b11 == 0b11 { 4 } else { 2 };
+                    Action::Resume
+                }
+                Err(err) => {
+                    log::error!("failed to read the instruction at {:#x}: {:?}", ctx.pc, err);
+                    Action::Kill
+                }
+            }
+        }
+        Trap::Exception(
+            exception @ (Exception::InstructionPageFault
+            | Exception::LoadPageFault
+            | Exception::StorePageFault),
+        ) => {
+            let access = match exception {
+                Exception::InstructionPageFault => MapPermission::X,
+                Exception::LoadPageFault => MapPermission::R,
+                _ => MapPermission::W,
+            };
+            // 懒分配的页在这里映射
+            match space.handle_page_fault(stval, access) {
+                Ok(()) => Action::Resume,
+                Err(err) => {
+                    log::error!(
+                        "segmentation fault ({:?}): {:?} at {:#x}, bad addr = {:#x}",
+                        err, exception, ctx.pc, stval
+                    );
+                    Action::Kill
+                }
+            }
+        }
+        Trap::Exception(
+            Exception::InstructionFault
+            | Exception::LoadFault
+            | Exception::StoreFault,
+        ) => {
+            log::error!("{:?} at {:#x}, bad addr = {:#x}", scause.cause(), ctx.pc, stval);
+            Action::Kill
+        }
+        Trap::Exception(Exception::IllegalInstruction) => {
+            log::error!("illegal instruction {:#x} at {:#x}", stval, ctx.pc);
+            Action::Kill
+        }
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            // 时间片用完，换下一个进程
+            Action::Yield
+        }
+        Trap::Interrupt(Interrupt::SupervisorSoft) => {
+            // 清除 sip.SSIP
+            unsafe { core::arch::asm!("csrci sip, 2") };
+            Action::Resume
+        }
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            log::warn!("unhandled external interrupt");
+            Action::Resume
+        }
+        _ => {
+            log::error!("unsupported trap {:?}, stval = {:#x}", scause.cause(), stval);
+            Action::Kill
+        }
+    }
+}
@@ -16,7 +16,9 @@ SECTIONS {
         *(.text.entry)
         . = ALIGN(4K);
         svdso = .;
+        KEEP(*(.text.vdso.table));
         *(.text.vdso);
+        *libvdso-*.rlib:*(.text .text.* .rodata .rodata.* .srodata .srodata.*)
         . = ALIGN(4K);
         evdso = .;
         . = ALIGN(4K);
@@ -0,0 +1,60 @@
+//! 系统调用号
+//!
+//! 默认使用本项目自己的编号，开启 `linux-abi` 特性之后使用 Linux riscv64 通用 ABI 的编号，
+//! 以便直接运行 musl/newlib 编译的静态程序。
+
+#[cfg(not(feature = "linux-abi"))]
+mod numbers {
+    pub const READ: usize = 4;
+    pub const WRITE: usize = 5;
+    pub const EXIT: usize = 93;
+    pub const EXIT_GROUP: usize = 94;
+    pub const SLEEP: usize = 101;
+    pub const NOTIFY_AFTER: usize = 102;
+    pub const SCHED_YIELD: usize = 124;
+    pub const KILL: usize = 129;
+    pub const GET_TIME: usize = 169;
+    pub const GETPID: usize = 172;
+    pub const SBRK: usize = 213;
+    pub const BRK: usize = 214;
+    pub const MUNMAP: usize = 215;
+    pub const FORK: usize = 220;
+    pub const MMAP: usize = 222;
+    pub const MPROTECT: usize = 226;
+    pub const PREEMPT_RETURN: usize = 139;
+    pub const WAITPID: usize = 260;
+}
+
+#[cfg(feature = "linux-abi")]
+mod numbers {
+    /// Linux 中没有对应编号的系统调用从这里开始分配，避免和 Linux 的编号冲突
+    const PRIVATE_BASE: usize = 0x1000;
+
+    pub const READ: usize = 63;
+    pub const WRITE: usize = 64;
+    pub const EXIT: usize = 93;
+    pub const EXIT_GROUP: usize = 94;
+    pub const SCHED_YIELD: usize = 124;
+    pub const GETPID: usize = 172;
+    pub const BRK: usize = 214;
+    pub const MUNMAP: usize = 215;
+    /// 只支持匿名映射，忽略 flags、fd 和 offset
+    pub const MMAP: usize = 222;
+    pub const MPROTECT: usize = 226;
+    pub const GET_TIME: usize = PRIVATE_BASE;
+    pub const PREEMPT_RETURN: usize = PRIVATE_BASE + 1;
+    /// Linux 的 nanosleep 使用 timespec，这里直接传递毫秒数
+    pub const SLEEP: usize = PRIVATE_BASE + 2;
+    /// Linux 的 wait4 返回编码过的状态，这里直接返回退出码
+    pub const WAITPID: usize = PRIVATE_BASE + 3;
+    /// 没有信号，直接杀死进程
+    pub const KILL: usize = PRIVATE_BASE + 4;
+    /// Linux 通过 clone 实现 fork，参数不同
+    pub const FORK: usize = PRIVATE_BASE + 5;
+    /// Linux 中 sbrk 由 libc 通过 brk 实现
+    pub const SBRK: usize = PRIVATE_BASE + 6;
+    /// 唤醒 vDSO 执行器中的协程，Linux 中没有对应的系统调用
+    pub const NOTIFY_AFTER: usize = PRIVATE_BASE + 7;
+}
+
+pub use numbers::*;
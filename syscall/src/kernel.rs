@@ -0,0 +1,29 @@
+/// 用户地址空间中的只读切片，内核需要经过地址转换才能访问
+#[derive(Debug, Clone, Copy)]
+pub struct UserSlice<T> {
+    /// 起始地址
+    pub ptr: *const T,
+    /// 元素个数
+    pub len: usize,
+}
+
+impl<T> UserSlice<T> {
+    pub fn new(ptr: *const T, len: usize) -> Self {
+        Self { ptr, len }
+    }
+}
+
+/// 用户地址空间中的可变切片，内核需要经过地址转换才能访问
+#[derive(Debug, Clone, Copy)]
+pub struct UserSliceMut<T> {
+    /// 起始地址
+    pub ptr: *mut T,
+    /// 元素个数
+    pub len: usize,
+}
+
+impl<T> UserSliceMut<T> {
+    pub fn new(ptr: *mut T, len: usize) -> Self {
+        Self { ptr, len }
+    }
+}
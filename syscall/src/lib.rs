@@ -7,19 +7,65 @@
 extern crate syscall_macro;
 
 
+mod id;
 mod kernel;
 mod user;
 
+pub use kernel::{UserSlice, UserSliceMut};
+
 use syscall_macro::SyscallMacro;
 
+/// 系统调用，编号见 `id` 模块
 #[repr(usize)]
 #[derive(Debug)]
 #[derive(SyscallMacro)]
 pub enum SyscallId{
-    #[arguments(args = "fd, buffer_ptr, buffer_len")]
-	read = 4,
-    #[arguments(args = "ffff")]
-    write = 5,
+    #[arguments(fd: usize, buffer: &mut [u8])]
+	read = id::READ,
+    #[arguments(fd: usize, buffer: &[u8])]
+    write = id::WRITE,
+    /// 结束当前进程
+    #[arguments(exit_code: i32)]
+    exit = id::EXIT,
+    /// 结束整个进程，内核中每个进程只有一个线程，和 `exit` 相同
+    #[arguments(exit_code: i32)]
+    exit_group = id::EXIT_GROUP,
+    /// 复制当前进程，父进程返回子进程的编号，子进程返回 0
+    fork = id::FORK,
+    /// 等待子进程结束并回收，`pid` 为 -1 时等待任意子进程，返回子进程的编号
+    #[arguments(pid: isize, exit_code: *mut i32)]
+    waitpid = id::WAITPID,
+    /// 设置程序断点，`addr` 为 0 时只查询，返回新的程序断点，失败时返回原来的程序断点
+    #[arguments(addr: usize)]
+    brk = id::BRK,
+    /// 程序断点移动 `increment` 字节，返回原来的程序断点
+    #[arguments(increment: isize)]
+    sbrk = id::SBRK,
+    /// 映射匿名内存，`start` 为 0 时由内核选择地址，返回映射的起始地址
+    #[arguments(start: usize, len: usize, prot: usize)]
+    mmap = id::MMAP,
+    /// 取消映射
+    #[arguments(start: usize, len: usize)]
+    munmap = id::MUNMAP,
+    /// 修改映射的权限
+    #[arguments(start: usize, len: usize, prot: usize)]
+    mprotect = id::MPROTECT,
+    /// 当前进程的编号
+    getpid = id::GETPID,
+    /// 杀死进程
+    #[arguments(pid: isize)]
+    kill = id::KILL,
+    sched_yield = id::SCHED_YIELD,
+    /// 进程睡眠 `ms` 毫秒
+    #[arguments(ms: usize)]
+    sleep = id::SLEEP,
+    /// `ms` 毫秒之后通过共享页唤醒 vDSO 执行器中编号为 `coroutine` 的协程，不会挂起进程
+    #[arguments(ms: usize, coroutine: usize)]
+    notify_after = id::NOTIFY_AFTER,
+    get_time = id::GET_TIME,
+    /// vDSO 处理完抢占之后，从用户栈上的上下文恢复执行
+    #[arguments(frame: usize)]
+    preempt_return = id::PREEMPT_RETURN,
 }
 
 macro_rules! syscall {
@@ -1,70 +1,183 @@
-
 mod syscall;
 
 extern crate alloc;
 extern crate proc_macro;
-use alloc::vec::Vec;
+use alloc::{collections::BTreeMap, vec::Vec};
 use proc_macro::TokenStream;
-use proc_macro2::Span;
-use quote::quote;
+use quote::{quote, ToTokens};
 use syn::{parse_macro_input, DeriveInput};
-use syscall::Arguments;
+use syscall::{ArgKind, Arguments};
+
+/// `syscall6` 最多只能传递 6 个参数
+const MAX_ARGS: usize = 6;
+
+/// 设置这个环境变量之后，编译时打印展开的代码
+const DEBUG_ENV: &str = "SYSCALL_MACRO_DEBUG";
 
 // SyscallMacro 定义
 #[proc_macro_derive(SyscallMacro, attributes(arguments))]
 pub fn syscall_macro_derive(input: TokenStream) -> TokenStream {
     let input: DeriveInput = parse_macro_input!(input);
     // ident 当前枚举名称
-    let DeriveInput { ident, .. } = input;
+    let DeriveInput { ident, data, .. } = input;
+    let variants = match data {
+        syn::Data::Enum(syn::DataEnum { variants, .. }) => variants,
+        _ => {
+            return syn::Error::new_spanned(&ident, "SyscallMacro can only be derived for enums")
+                .to_compile_error()
+                .into()
+        }
+    };
     let mut comment_arms = Vec::new();
-    if let syn::Data::Enum(syn::DataEnum { variants, .. }) = input.data {
-        for variant in variants {
-            // 当前枚举项名称如 Alex, Box
-            let ident_item = &variant.ident;
-            if let Ok(args) = Arguments::from_attributes(&variant.attrs) {
-                // 获取属性中定义的参数信息
-                let args_str = args.args.unwrap().value();
-                let args_vec: Vec<syn::Ident> = args_str.split(", ").map(|s| syn::Ident::new(s, Span::call_site())).collect();
-                let len = args_vec.len();
-                let syscall_fn = quote::format_ident!("syscall{}", len);
-                eprintln!("{}", quote!(
-                    #syscall_fn(#ident::#ident_item as usize, #($#args_vec),*)
-                ));
-                let mut doc = String::from("参数类型为 ");
-                for  idx in 0..(len - 1) {
-                    doc.push_str(&args_vec[idx].to_string().as_str());
-                    doc.push_str(": usize, ");
-                }
-                doc.push_str(&args_vec[len - 1].to_string().as_str());
-                doc.push_str(": usize");
-                eprintln!("{}", doc);
-                // 生成对应的宏
-                comment_arms.push(quote! (
-                    #[doc = #doc]
-                    #[macro_export]
-                    macro_rules ! #ident_item {
-                        (#($#args_vec: expr),*) => {
-                            unsafe {
-                                #syscall_fn(#ident::#ident_item as usize, #($#args_vec),*)
-                            }
-                            // log::error!("{}", concat!(#($#args_vec),*));
-                        }
+    // 内核处理函数的声明以及分发的分支
+    let mut handler_fns = Vec::new();
+    let mut dispatch_arms = Vec::new();
+    // 收集所有的错误一起报告
+    let mut errors: Option<syn::Error> = None;
+    let mut report = |err: syn::Error| match &mut errors {
+        Some(errors) => errors.combine(err),
+        None => errors = Some(err),
+    };
+    // 已经使用的系统调用号
+    let mut ids = BTreeMap::new();
+    for variant in &variants {
+        // 当前枚举项名称如 Alex, Box
+        let ident_item = &variant.ident;
+        // 系统调用号可以是整数，也可以是 `id::READ` 这样的常量，常量按照路径比较
+        let id = match &variant.discriminant {
+            Some((_, syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Int(lit), .. }))) => {
+                match lit.base10_parse::<usize>() {
+                    Ok(id) => Some(id.to_string()),
+                    Err(err) => {
+                        report(err);
+                        None
                     }
+                }
+            }
+            Some((_, syn::Expr::Path(path))) => Some(
+                path.path
+                    .segments
+                    .iter()
+                    .map(|segment| segment.ident.to_string())
+                    .collect::<Vec<_>>()
+                    .join("::"),
+            ),
+            _ => None,
+        };
+        if let (Some(id), Some((_, expr))) = (id, &variant.discriminant) {
+            if let Some(first) = ids.insert(id.clone(), ident_item) {
+                report(syn::Error::new_spanned(
+                    expr,
+                    format!("duplicate syscall id {}, already used by `{}`", id, first),
                 ));
-            } else {
-                comment_arms.push(quote! ( 
-                    #[macro_export]
-                    macro_rules ! #ident_item {
-                        () => {
-                            unsafe {
-                                syscall0(#ident::#ident_item as usize)
-                            }
-                        }
+            }
+        }
+        // 获取属性中定义的参数信息，没有属性的系统调用没有参数
+        let mut attrs = variant.attrs.iter().filter(|attr| attr.path.is_ident("arguments"));
+        let arguments = attrs.next();
+        let args = match arguments {
+            Some(attr) => match attr.parse_args::<Arguments>() {
+                Ok(args) => args.0,
+                Err(err) => {
+                    report(err);
+                    continue;
+                }
+            },
+            None => Vec::new(),
+        };
+        for attr in attrs {
+            report(syn::Error::new_spanned(attr, "duplicate `arguments` attribute"));
+        }
+        let len: usize = args.iter().map(|arg| arg.regs()).sum();
+        if len > MAX_ARGS {
+            let message = format!(
+                "syscall `{}` needs {} argument registers, but at most {} are supported",
+                ident_item, len, MAX_ARGS
+            );
+            report(match arguments {
+                Some(attr) => syn::Error::new_spanned(attr, message),
+                None => syn::Error::new_spanned(variant, message),
+            });
+            continue;
+        }
+        let names: Vec<_> = args.iter().map(|arg| &arg.name).collect();
+        let tys: Vec<_> = args.iter().map(|arg| &arg.ty).collect();
+        let user_regs: Vec<_> = args.iter().map(|arg| arg.user_regs()).collect();
+        let syscall_fn = quote::format_ident!("syscall{}", len);
+        let params: Vec<_> = args
+            .iter()
+            .map(|arg| format!("{}: {}", arg.name, arg.ty.to_token_stream()))
+            .collect();
+        let doc = if params.is_empty() {
+            String::from("没有参数")
+        } else {
+            format!("参数类型为 {}", params.join(", "))
+        };
+        // 带有裸指针参数的系统调用需要调用者保证地址有效
+        let unsafety = args
+            .iter()
+            .any(|arg| matches!(arg.kind, ArgKind::Pointer))
+            .then(|| quote!(unsafe));
+        // 内核处理函数以及从寄存器中取出参数的分支
+        let handler_tys: Vec<_> = args.iter().map(|arg| arg.handler_ty()).collect();
+        let mut idx = 0;
+        let kernel_args: Vec<_> = args
+            .iter()
+            .map(|arg| {
+                let expr = arg.kernel_arg(idx);
+                idx += arg.regs();
+                expr
+            })
+            .collect();
+        handler_fns.push(quote!(
+            #[doc = #doc]
+            fn #ident_item(&mut self, #(#names: #handler_tys),*) -> isize;
+        ));
+        dispatch_arms.push(quote!(
+            id if id == #ident::#ident_item as usize => Some(handler.#ident_item(#(#kernel_args),*)),
+        ));
+        // 生成用户态的函数以及对应的宏
+        comment_arms.push(quote! (
+            #[doc = #doc]
+            pub #unsafety fn #ident_item(#(#names: #tys),*) -> isize {
+                unsafe {
+                    #syscall_fn(#ident::#ident_item as usize, #(#user_regs),*)
+                }
+            }
+
+            #[doc = #doc]
+            #[macro_export]
+            macro_rules ! #ident_item {
+                (#($#names: expr),*) => {
+                    #unsafety {
+                        $crate::#ident_item(#($#names),*)
                     }
-                ));
+                }
+            }
+        ));
+    }
+    if let Some(errors) = errors {
+        return errors.to_compile_error().into();
+    }
+    let expanded = quote!(
+        #(#comment_arms)*
+
+        /// 内核实现的系统调用，每个系统调用号对应一个处理函数
+        pub trait SyscallHandler {
+            #(#handler_fns)*
+        }
+
+        /// 根据 a7 中的系统调用号，从 a0..a5 中取出参数分发到对应的处理函数，
+        /// 不存在的系统调用返回 `None`
+        pub fn dispatch<H: SyscallHandler + ?Sized>(handler: &mut H, a: &[usize; 8]) -> Option<isize> {
+            match a[7] {
+                #(#dispatch_arms)*
+                _ => None,
             }
-            
         }
+    );
+    if std::env::var_os(DEBUG_ENV).is_some() {
+        eprintln!("{}", expanded);
     }
-    quote!(#(#comment_arms)*).into()
-}
\ No newline at end of file
+    expanded.into()
+}
@@ -1,7 +1,129 @@
-use bae::FromAttributes;
-use syn;
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    Ident, Token, Type,
+};
 
-#[derive(Default, FromAttributes, Debug)]
-pub struct Arguments {
-    pub args: Option<syn::LitStr>,
-}
\ No newline at end of file
+/// 直接用一个寄存器传递的整数类型
+const SCALARS: [&str; 10] = [
+    "usize", "isize", "u8", "u16", "u32", "u64", "i8", "i16", "i32", "i64",
+];
+
+/// 参数在寄存器中的传递方式
+pub enum ArgKind {
+    /// 整数，用一个寄存器传递
+    Scalar,
+    /// 裸指针，用一个寄存器传递地址
+    Pointer,
+    /// 切片或者字符串，用两个寄存器传递地址和长度
+    Slice { elem: TokenStream, mutable: bool },
+}
+
+/// `#[arguments(fd: usize, buffer: &[u8])]` 中的一个参数
+pub struct Argument {
+    pub name: Ident,
+    pub ty: Type,
+    pub kind: ArgKind,
+}
+
+impl Parse for Argument {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let ty: Type = input.parse()?;
+        let kind = ArgKind::from_type(&ty)?;
+        Ok(Self { name, ty, kind })
+    }
+}
+
+impl ArgKind {
+    fn from_type(ty: &Type) -> syn::Result<Self> {
+        match ty {
+            Type::Path(path) if path.qself.is_none() => {
+                if SCALARS.iter().any(|s| path.path.is_ident(s)) {
+                    return Ok(Self::Scalar);
+                }
+                if path.path.is_ident("str") {
+                    return Err(syn::Error::new_spanned(ty, "use `&str` instead of `str`"));
+                }
+            }
+            Type::Ptr(_) => return Ok(Self::Pointer),
+            Type::Reference(reference) => {
+                let mutable = reference.mutability.is_some();
+                match reference.elem.as_ref() {
+                    Type::Slice(slice) => {
+                        let elem = &slice.elem;
+                        return Ok(Self::Slice { elem: quote!(#elem), mutable });
+                    }
+                    Type::Path(path) if !mutable && path.path.is_ident("str") => {
+                        return Ok(Self::Slice { elem: quote!(u8), mutable });
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        Err(syn::Error::new_spanned(
+            ty,
+            "unsupported syscall argument type, expected an integer, a raw pointer, a slice or `&str`",
+        ))
+    }
+}
+
+impl Argument {
+    /// 占用的寄存器数量
+    pub fn regs(&self) -> usize {
+        match self.kind {
+            ArgKind::Slice { .. } => 2,
+            _ => 1,
+        }
+    }
+
+    /// 用户态：把参数转换成寄存器中的值
+    pub fn user_regs(&self) -> TokenStream {
+        let name = &self.name;
+        match &self.kind {
+            ArgKind::Scalar | ArgKind::Pointer => quote!(#name as usize),
+            ArgKind::Slice { mutable: true, .. } => quote!(#name.as_mut_ptr() as usize, #name.len()),
+            ArgKind::Slice { mutable: false, .. } => quote!(#name.as_ptr() as usize, #name.len()),
+        }
+    }
+
+    /// 内核处理函数中的参数类型，切片在内核中只是用户地址空间中的一段地址
+    pub fn handler_ty(&self) -> TokenStream {
+        let ty = &self.ty;
+        match &self.kind {
+            ArgKind::Scalar | ArgKind::Pointer => quote!(#ty),
+            ArgKind::Slice { elem, mutable: true } => quote!(crate::UserSliceMut<#elem>),
+            ArgKind::Slice { elem, mutable: false } => quote!(crate::UserSlice<#elem>),
+        }
+    }
+
+    /// 内核态：从 `a[idx..]` 中恢复参数
+    pub fn kernel_arg(&self, idx: usize) -> TokenStream {
+        let ty = &self.ty;
+        match &self.kind {
+            ArgKind::Scalar | ArgKind::Pointer => quote!(a[#idx] as #ty),
+            ArgKind::Slice { elem, mutable: true } => {
+                let len = idx + 1;
+                quote!(crate::UserSliceMut::new(a[#idx] as *mut #elem, a[#len]))
+            }
+            ArgKind::Slice { elem, mutable: false } => {
+                let len = idx + 1;
+                quote!(crate::UserSlice::new(a[#idx] as *const #elem, a[#len]))
+            }
+        }
+    }
+}
+
+/// 系统调用的参数列表
+pub struct Arguments(pub Vec<Argument>);
+
+impl Parse for Arguments {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let args = Punctuated::<Argument, Token![,]>::parse_terminated(input)?;
+        Ok(Self(args.into_iter().collect()))
+    }
+}
@@ -0,0 +1,7 @@
+//! 派生宏在错误输入下的编译错误
+
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
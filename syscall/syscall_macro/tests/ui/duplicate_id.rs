@@ -0,0 +1,14 @@
+#![allow(non_camel_case_types)]
+
+use syscall_macro::SyscallMacro;
+
+#[repr(usize)]
+#[derive(SyscallMacro)]
+pub enum SyscallId {
+    #[arguments(fd: usize, buffer: &mut [u8])]
+    read = 4,
+    #[arguments(fd: usize, buffer: &[u8])]
+    write = 4,
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+#![allow(non_camel_case_types)]
+
+use syscall_macro::SyscallMacro;
+
+mod id {
+    pub const READ: usize = 63;
+}
+
+#[repr(usize)]
+#[derive(SyscallMacro)]
+pub enum SyscallId {
+    #[arguments(fd: usize, buffer: &mut [u8])]
+    read = id::READ,
+    #[arguments(fd: usize, buffer: &[u8])]
+    write = id::READ,
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use syscall_macro::SyscallMacro;
+
+#[derive(SyscallMacro)]
+pub struct SyscallId(usize);
+
+fn main() {}
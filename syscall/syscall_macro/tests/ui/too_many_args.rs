@@ -0,0 +1,12 @@
+#![allow(non_camel_case_types)]
+
+use syscall_macro::SyscallMacro;
+
+#[repr(usize)]
+#[derive(SyscallMacro)]
+pub enum SyscallId {
+    #[arguments(a: usize, b: &[u8], c: &[u8], d: &str)]
+    mmap = 222,
+}
+
+fn main() {}
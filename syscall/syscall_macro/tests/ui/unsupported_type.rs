@@ -0,0 +1,12 @@
+#![allow(non_camel_case_types)]
+
+use syscall_macro::SyscallMacro;
+
+#[repr(usize)]
+#[derive(SyscallMacro)]
+pub enum SyscallId {
+    #[arguments(fd: usize, buffer: Vec<u8>)]
+    write = 5,
+}
+
+fn main() {}
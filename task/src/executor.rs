@@ -0,0 +1,117 @@
+//! 内核协程
+//!
+//! 会阻塞的系统调用不在内核中忙等，而是把进程挂起，把等待的操作作为内核协程交给执行器。
+//! 每个内核协程都属于一个进程，协程完成之后结果作为系统调用的返回值，进程重新就绪；
+//! 也可以不挂起进程，协程完成之后通过共享页唤醒进程中的用户协程。
+//! 空闲的核在调度循环中轮询被唤醒的协程。
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, task::Wake};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use super::{Process, ProcessState};
+
+type BoxFuture = Pin<Box<dyn Future<Output = isize> + Send>>;
+
+/// 内核协程完成之后的去向
+#[derive(Clone, Copy)]
+enum Completion {
+    /// 唤醒被挂起的进程
+    Resume,
+    /// 唤醒进程中编号为这个值的用户协程
+    Notify(usize),
+}
+
+/// 内核协程
+struct Coroutine {
+    /// 完成之后为 `None`
+    future: Mutex<Option<BoxFuture>>,
+    /// 等待这个协程的进程
+    process: Arc<Process>,
+    completion: Completion,
+    /// 已经在就绪队列中，避免重复加入
+    queued: AtomicBool,
+}
+
+impl Wake for Coroutine {
+    fn wake(self: Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            READY.lock().push_back(self);
+        }
+    }
+}
+
+/// 被唤醒等待轮询的内核协程
+///
+/// 锁的顺序：进程的 `inner` 在前，`READY` 在后。持有 `READY` 时不能锁住进程，也不能轮询协程，
+/// 因此持有进程锁的系统调用可以唤醒或者创建内核协程
+static READY: Mutex<VecDeque<Arc<Coroutine>>> = Mutex::new(VecDeque::new());
+
+/// 挂起进程，在内核协程中等待 `future` 完成
+///
+/// `future` 的结果写入进程上下文的 a0，作为系统调用的返回值
+pub fn park<F>(process: Arc<Process>, future: F)
+where
+    F: Future<Output = isize> + Send + 'static,
+{
+    // 先阻塞进程再加入执行器，协程完成时进程一定已经被挂起
+    process.inner.lock().set_state(ProcessState::Blocked);
+    spawn(process, future, Completion::Resume);
+}
+
+/// 不挂起进程，在内核协程中等待 `future` 完成之后唤醒进程中编号为 `coroutine` 的用户协程
+///
+/// 不需要锁住进程，处理系统调用时持有着进程锁也可以调用，见 [`READY`] 的锁顺序
+pub fn spawn_notify<F>(process: Arc<Process>, coroutine: usize, future: F)
+where
+    F: Future<Output = isize> + Send + 'static,
+{
+    spawn(process, future, Completion::Notify(coroutine));
+}
+
+fn spawn<F>(process: Arc<Process>, future: F, completion: Completion)
+where
+    F: Future<Output = isize> + Send + 'static,
+{
+    let coroutine = Arc::new(Coroutine {
+        future: Mutex::new(Some(Box::pin(future))),
+        process,
+        completion,
+        queued: AtomicBool::new(false),
+    });
+    coroutine.wake();
+}
+
+/// 轮询所有被唤醒的内核协程，返回是否轮询了协程
+pub fn run_coroutines() -> bool {
+    let mut polled = false;
+    loop {
+        // 不能在轮询时持有就绪队列的锁，轮询和唤醒都会锁住它
+        let next = READY.lock().pop_front();
+        let Some(coroutine) = next else { break };
+        polled = true;
+        // 在轮询之前清除标记，轮询期间的唤醒会重新加入就绪队列
+        coroutine.queued.store(false, Ordering::Release);
+        let waker = Waker::from(coroutine.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut slot = coroutine.future.lock();
+        let ret = match slot.as_mut() {
+            Some(future) => match future.as_mut().poll(&mut cx) {
+                Poll::Ready(ret) => ret,
+                Poll::Pending => continue,
+            },
+            None => continue,
+        };
+        *slot = None;
+        drop(slot);
+        match coroutine.completion {
+            Completion::Resume => coroutine.process.clone().wake(ret),
+            Completion::Notify(id) => coroutine.process.inner.lock().notify(id),
+        }
+    }
+    polled
+}
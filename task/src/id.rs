@@ -1,17 +1,49 @@
+use alloc::vec::Vec;
+use spin::Mutex;
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+/// 可以回收的编号分配器
+struct RecycleAllocator {
+    /// 还没有分配过的最小编号
+    current: usize,
+    /// 回收的编号
+    recycled: Vec<usize>,
+}
+
+impl RecycleAllocator {
+    const fn new() -> Self {
+        Self { current: 0, recycled: Vec::new() }
+    }
+
+    /// 优先复用回收的编号
+    fn alloc(&mut self) -> usize {
+        self.recycled.pop().unwrap_or_else(|| {
+            self.current += 1;
+            self.current - 1
+        })
+    }
+
+    fn dealloc(&mut self, id: usize) {
+        assert!(id < self.current, "id {} has not been allocated", id);
+        assert!(!self.recycled.contains(&id), "id {} has been deallocated", id);
+        self.recycled.push(id);
+    }
+}
+
+/// 进程编号分配器
+static PID_ALLOCATOR: Mutex<RecycleAllocator> = Mutex::new(RecycleAllocator::new());
 
 /// 进程 Id
 #[derive(Eq, PartialEq, Debug, Clone, Copy, Hash, Ord, PartialOrd)]
 pub struct ProcId(usize);
 
 impl ProcId {
-    ///
+    /// 分配一个进程编号，回收的编号会被复用
     pub fn new() -> Self {
-        // 任务编号计数器，任务编号自增
-        static PID_COUNTER: AtomicUsize = AtomicUsize::new(0);
-        let id = PID_COUNTER.fetch_add(1, Ordering::Relaxed);
-        Self(id)
+        Self(PID_ALLOCATOR.lock().alloc())
+    }
+    /// 回收进程编号，进程被回收之后才能调用
+    pub(crate) fn dealloc(self) {
+        PID_ALLOCATOR.lock().dealloc(self.0);
     }
     ///
     pub fn from_usize(v: usize) -> Self {
@@ -21,4 +53,4 @@ impl ProcId {
     pub fn get_usize(&self) -> usize {
         self.0
     }
-}
\ No newline at end of file
+}
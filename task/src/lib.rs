@@ -4,7 +4,13 @@
 extern crate alloc;
 
 mod process;
+mod processor;
+mod scheduler;
+mod executor;
 mod id;
 
-use id::ProcId;
-pub use process::Process;
\ No newline at end of file
+pub use id::ProcId;
+pub use process::{Process, ProcessState, process_num, find_process, exit_killed};
+pub use processor::{hart_id, current, set_current};
+pub use scheduler::{Scheduler, FifoScheduler, PriorityScheduler, set_scheduler, add_process, fetch_process};
+pub use executor::{park, spawn_notify, run_coroutines};
@@ -1,12 +1,77 @@
 
 use core::ptr::NonNull;
 
-use alloc::{sync::Arc};
-use config::TRAMPOLINE;
-use fast_trap::{Stack, FlowContext, alloc_stack, restore, trap_entry};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+use alloc::{collections::BTreeMap, sync::{Arc, Weak}, vec::Vec};
+use config::{USER_STACK_SIZE, SHARED_PAGE, PAGE_SIZE, EXECUTOR_BASE, EXECUTOR_SIZE, THREAD_STACK_BASE};
+use fast_trap::{Stack, FlowContext, alloc_stack, dealloc_stack, restore, portal_addr};
+use riscv::register::sstatus::{self, SPP};
 use spin::Mutex;
-use vmm::{MemorySet};
-use super::ProcId;
+use vdso::SharedData;
+use vmm::{MemorySet, MapPermission, VirtAddr, VmError, VmResult, PORTAL_POOL};
+use super::{ProcId, hart_id, add_process};
+
+/// 还没有被回收的进程，进程被回收时移除
+static PROCESSES: Mutex<BTreeMap<ProcId, Weak<Process>>> = Mutex::new(BTreeMap::new());
+
+/// 被杀死时处于阻塞状态的进程，等待调度循环结束它们
+static KILLED: Mutex<Vec<Arc<Process>>> = Mutex::new(Vec::new());
+
+/// 结束被杀死的阻塞进程
+///
+/// 结束进程需要锁住父进程，而处理系统调用时持有着调用者的锁，
+/// 调用者杀死自己的子进程时不能在系统调用中结束它，由调度循环调用这个函数
+pub fn exit_killed() {
+    let killed = core::mem::take(&mut *KILLED.lock());
+    for process in killed {
+        process.exit(-1);
+    }
+}
+
+/// 还没有结束的进程数量，进程创建时加一，成为僵尸进程时减一
+static ALIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// 还没有结束的进程数量，包括正在运行、就绪和阻塞的进程
+///
+/// 不包括等待回收的僵尸进程，父进程可能永远不会回收它们
+pub fn process_num() -> usize {
+    ALIVE.load(Ordering::Acquire)
+}
+
+/// 根据编号找到还没有被回收的进程
+pub fn find_process(pid: ProcId) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).and_then(Weak::upgrade)
+}
+
+/// 进程的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    /// 第一阶段初始化完成，已经建立地址空间
+    Created,
+    /// 第二阶段初始化完成，绑定了这个核的异界传送门槽，等待运行
+    Prepared(usize),
+    /// 正在运行
+    Running,
+    /// 等待某个事件，不在就绪队列中
+    Blocked,
+    /// 已经结束，等待回收
+    Zombie,
+}
+
+impl ProcessState {
+    /// 是否可以转换到 `next`
+    pub fn can_become(self, next: Self) -> bool {
+        use ProcessState::*;
+        matches!(
+            (self, next),
+            (Created | Prepared(_) | Blocked, Prepared(_))
+                | (Running, Prepared(_) | Blocked)
+                | (Prepared(_), Running)
+                | (Created | Prepared(_) | Running | Blocked, Zombie)
+        )
+    }
+}
 
 pub struct Process {
     pub pid: ProcId,
@@ -17,48 +82,392 @@ pub struct ProcessInner {
     pub space: MemorySet,
     pub stack: NonNull<Stack>,
     pub ctx: NonNull<FlowContext>,
+    /// 共享页在内核地址空间中的位置
+    pub shared: NonNull<SharedData>,
+    /// 最近一次陷入时进程报告的优先级，越小越紧急
+    pub priority: usize,
+    state: ProcessState,
+    /// ELF 的入口，第二阶段初始化时写入上下文
+    entry: usize,
+    /// 用户栈顶，第二阶段初始化时写入上下文
+    user_stack_top: usize,
+    /// 父进程，父进程先结束之后为 `None`
+    parent: Option<Weak<Process>>,
+    /// 还没有被回收的子进程
+    children: Vec<Arc<Process>>,
+    /// 退出码，进程结束之后有效
+    exit_code: i32,
+    /// 等待子进程结束的内核协程
+    waiters: Vec<Waker>,
+    /// 被其他进程杀死，下一次进入内核或者被调度时结束
+    killed: bool,
+}
+
+/// 栈和上下文只会被持有进程锁的控制流访问
+unsafe impl Send for ProcessInner {}
+
+impl ProcessInner {
+    /// 从共享页中读取用户态报告的优先级
+    pub fn sync_priority(&mut self) -> usize {
+        self.priority = unsafe { core::ptr::read_volatile(&self.shared.as_ref().priority) };
+        self.priority
+    }
+
+    /// 通过共享页唤醒用户态执行器中的协程
+    ///
+    /// 进程下一次返回用户态时，vDSO 中的执行器会根据优先级决定是否抢占正在执行的协程
+    pub fn notify(&self, coroutine: usize) {
+        // 结束的进程已经回收了共享页
+        if self.state == ProcessState::Zombie || coroutine >= vdso::MAX_COROUTINES {
+            return;
+        }
+        let shared = unsafe { self.shared.as_ref() };
+        shared.pending.fetch_or(1 << coroutine, Ordering::Release);
+    }
+
+    /// 需要抢占协程时，`restore` 会把上下文保存在用户栈上，提前分配这部分用户栈
+    ///
+    /// 在 S 态访问没有分配的用户栈会导致进程被杀死，因此只有分配成功之后才设置抢占标志。
+    /// 释放进程锁之后其他核唤醒的协程不会在这次返回用户态时抢占
+    fn prefault_preempt_frame(&mut self) {
+        let shared = unsafe { self.shared.as_ref() };
+        let armed = shared.preempt_entry.load(Ordering::Acquire) != 0
+            && shared.pending.load(Ordering::Acquire) != 0
+            && {
+                let sp = unsafe { self.ctx.as_ref() }.sp;
+                let frame = sp.wrapping_sub(core::mem::size_of::<FlowContext>()) & !15;
+                [frame, sp.wrapping_sub(1)]
+                    .into_iter()
+                    .all(|va| self.space.handle_page_fault(va, MapPermission::R | MapPermission::W).is_ok())
+            };
+        unsafe { *self.stack.as_ref().preempt_flag().as_ptr() = armed as usize };
+    }
+
+    /// 进程当前的状态
+    pub fn state(&self) -> ProcessState {
+        self.state
+    }
+
+    /// 是否已经被杀死
+    pub fn is_killed(&self) -> bool {
+        self.killed
+    }
+
+    /// 转换进程的状态，不合法的转换说明内核出现了错误
+    pub fn set_state(&mut self, next: ProcessState) {
+        assert!(
+            self.state.can_become(next),
+            "invalid process state transition {:?} -> {:?}",
+            self.state,
+            next
+        );
+        self.state = next;
+    }
+
+    /// 第二阶段初始化，进程即将在第 `hartid` 个核上运行
+    ///
+    /// 绑定这个核的异界传送门槽，第一次运行时还要初始化 vDSO 的缺省上下文：
+    /// 从这个核的 vDSO 槽中的 `user_entry` 开始执行，a0 为 ELF 的入口，a1 为用户栈顶
+    pub fn prepare(&mut self, hartid: usize) {
+        if self.state == ProcessState::Prepared(hartid) {
+            return;
+        }
+        let portal = PORTAL_POOL.lock().get(hartid).expect("no portal for this hart");
+        // 异界传送门槽和 vDSO 槽与进程的栈共用最后一级页表，不需要再分配页表页
+        self.space
+            .bind_portal(portal)
+            .expect("page table of the high region is allocated with the stack");
+        if self.state == ProcessState::Created {
+            let ctx = unsafe { self.ctx.as_mut() };
+            ctx.pc = vdso::vdso_addr(hartid, vdso::user_entry as usize);
+            ctx.sp = self.user_stack_top;
+            ctx.a[0] = self.entry;
+            ctx.a[1] = self.user_stack_top;
+        }
+        self.set_state(ProcessState::Prepared(hartid));
+    }
 }
 
 impl Process {
 
-    pub fn execute(self: Arc<Self>) {
-        let satp = self.inner.lock().space.token();
-        let restore = restore as usize - trap_entry as usize + TRAMPOLINE;
-        log::info!("{:#x}", restore);
+    /// 切换到进程的地址空间，从栈顶的上下文恢复执行
+    ///
+    /// 还没有为当前核完成第二阶段初始化的进程先调用 [`ProcessInner::prepare`]，
+    /// 从内核返回的正在运行的进程不需要。
+    /// 调用者需要保证进程仍被 `CURRENT` 持有，这个函数不会返回
+    pub fn execute(self: Arc<Self>) -> ! {
+        let satp = {
+            let mut inner = self.inner.lock();
+            if inner.state != ProcessState::Running {
+                inner.prepare(hart_id());
+                inner.set_state(ProcessState::Running);
+            }
+            inner.prefault_preempt_frame();
+            inner.space.token()
+        };
+        // 控制流不会回到这里，需要提前释放引用
+        drop(self);
+        let restore = portal_addr(hart_id(), restore as usize);
         unsafe { 
+            sstatus::set_spp(SPP::User);
             core::arch::asm!(
                 "fence.i",
                 "jr a1",
                 in("a0") satp,
                 in("a1") restore,
+                options(noreturn),
             );
         }
     }
 
-    pub fn new() -> Arc<Self> {
-        let pid = ProcId::new();
-        let mut space = MemorySet::new_bare();
-        let stack;
-        if let Some(s) = alloc_stack() {
-            stack = s;
-        } else {
-            panic!("alloc stack failed");
+    /// 唤醒被挂起的进程，`ret` 作为系统调用的返回值，进程重新加入就绪队列
+    pub fn wake(self: Arc<Self>, ret: isize) {
+        {
+            let mut inner = self.inner.lock();
+            // 进程在阻塞期间被杀死
+            if inner.state == ProcessState::Zombie {
+                return;
+            }
+            unsafe { inner.ctx.as_mut() }.a[0] = ret as usize;
+            inner.prepare(hart_id());
         }
-        space.map_vdso();
-        space.map_trampoline();
-        space.map_stack(stack.as_ptr() as *mut usize as usize);
-        let mut ctx = unsafe { stack.as_ref().context() };
-        unsafe { 
-            ctx.as_mut().pc = vdso::user_entry as usize;
-            ctx.as_mut().sp = usize::MAX - core::mem::size_of::<FlowContext>() + 1;
+        add_process(self);
+    }
+
+    /// 把 `child` 作为这个进程的子进程
+    pub fn add_child(self: &Arc<Self>, child: Arc<Process>) {
+        child.inner.lock().parent = Some(Arc::downgrade(self));
+        self.inner.lock().children.push(child);
+    }
+
+    /// 结束进程，成为僵尸进程等待父进程回收
+    ///
+    /// 立即回收地址空间中的数据页和内核栈，页表随着进程一起回收。
+    /// 子进程不再有父进程，结束之后直接回收。已经结束的进程不受影响
+    pub fn exit(&self, exit_code: i32) {
+        let (parent, children, waiters) = {
+            let mut inner = self.inner.lock();
+            if inner.state == ProcessState::Zombie {
+                return;
+            }
+            inner.set_state(ProcessState::Zombie);
+            ALIVE.fetch_sub(1, Ordering::AcqRel);
+            inner.exit_code = exit_code;
+            inner.space.recycle_data_pages();
+            // 内核运行在每个核自己的栈上，进程的栈已经不会再被使用
+            dealloc_stack(inner.stack);
+            (
+                inner.parent.take(),
+                core::mem::take(&mut inner.children),
+                core::mem::take(&mut inner.waiters),
+            )
+        };
+        for child in children {
+            child.inner.lock().parent = None;
+        }
+        // 唤醒自己的等待者，让等待子进程的内核协程完成
+        for waiter in waiters {
+            waiter.wake();
+        }
+        if let Some(parent) = parent.and_then(|parent| parent.upgrade()) {
+            let waiters = core::mem::take(&mut parent.inner.lock().waiters);
+            for waiter in waiters {
+                waiter.wake();
+            }
+        }
+    }
+
+    /// 杀死进程
+    ///
+    /// 阻塞的进程由 [`exit_killed`] 结束，其他进程在下一次进入内核或者被调度时结束
+    pub fn kill(self: &Arc<Self>) {
+        let blocked = {
+            let mut inner = self.inner.lock();
+            inner.killed = true;
+            inner.state == ProcessState::Blocked
         };
-        Arc::new(Self {
+        if blocked {
+            KILLED.lock().push(self.clone());
+        }
+    }
+
+    /// 回收一个已经结束的子进程，返回它的编号和退出码，`pid` 为 -1 时可以是任意子进程
+    ///
+    /// 没有符合条件的子进程时返回 `Ready(None)`，
+    /// 符合条件的子进程都还没有结束时记录 `waker`，有子进程结束时唤醒
+    pub fn poll_wait(&self, pid: isize, waker: &Waker) -> Poll<Option<(ProcId, i32)>> {
+        let mut inner = self.inner.lock();
+        let matches = |child: &Arc<Process>| pid == -1 || child.pid.get_usize() as isize == pid;
+        if !inner.children.iter().any(matches) {
+            return Poll::Ready(None);
+        }
+        let zombie = inner
+            .children
+            .iter()
+            .position(|child| matches(child) && child.inner.lock().state == ProcessState::Zombie);
+        match zombie {
+            Some(idx) => {
+                let child = inner.children.remove(idx);
+                let exit_code = child.inner.lock().exit_code;
+                Poll::Ready(Some((child.pid, exit_code)))
+            }
+            None => {
+                inner.waiters.push(waker.clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// 从 ELF 文件创建进程，即第一阶段初始化
+    ///
+    /// 映射 ELF 的各个段、用户栈、共享页、vDSO 执行器的内存和线程栈以及高位地址的栈和上下文，
+    /// 其中用户栈和线程栈是懒分配的，堆位于用户栈之上，一开始为空，
+    /// 异界传送门槽和 vDSO 在第二阶段初始化时绑定。
+    /// ELF 不合法或者内存不足时返回错误，此时还没有分配进程编号
+    pub fn from_elf(elf_data: &[u8]) -> Result<Arc<Self>, VmError> {
+        let (mut space, user_stack_base, entry) = MemorySet::from_elf(elf_data)?;
+        let user_stack_top = user_stack_base + USER_STACK_SIZE;
+        // 用户栈和线程栈在访问时才分配
+        space.insert_lazy_area(
+            user_stack_base.into(),
+            user_stack_top.into(),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )?;
+        // 堆和用户栈之间留出一页，防止两个区域被合并
+        space.init_brk(user_stack_top + PAGE_SIZE);
+        space.insert_framed_area(
+            EXECUTOR_BASE.into(),
+            (EXECUTOR_BASE + EXECUTOR_SIZE).into(),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )?;
+        space.insert_lazy_area(
+            THREAD_STACK_BASE.into(),
+            EXECUTOR_BASE.into(),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )?;
+        let shared = map_shared(&mut space)?;
+        let (stack, ctx) = map_stack(&mut space)?;
+        let pid = ProcId::new();
+        // 分配的栈没有清零
+        unsafe { ctx.as_ptr().write_bytes(0, 1) };
+        let process = Arc::new(Self {
             pid,
             inner: Mutex::new(ProcessInner {
                 space,
                 stack,
                 ctx,
+                shared,
+                priority: 0,
+                state: ProcessState::Created,
+                entry,
+                user_stack_top,
+                parent: None,
+                children: Vec::new(),
+                exit_code: 0,
+                waiters: Vec::new(),
+                killed: false,
             }),
-        })
-    } 
-}
\ No newline at end of file
+        });
+        PROCESSES.lock().insert(pid, Arc::downgrade(&process));
+        ALIVE.fetch_add(1, Ordering::AcqRel);
+        Ok(process)
+    }
+
+    /// 复制出一个子进程，子进程从系统调用返回 0
+    ///
+    /// 地址空间写时复制，共享页和进程的栈重新分配，
+    /// 子进程直接绑定当前核的异界传送门槽，从复制的上下文继续执行。
+    /// 内存不足时返回错误，父进程不受影响
+    pub fn fork(self: &Arc<Self>) -> Result<Arc<Self>, VmError> {
+        let hartid = hart_id();
+        let mut inner = self.inner.lock();
+        let mut space = MemorySet::from_existed_user(&mut inner.space)?;
+        // 内核直接访问共享页的物理页，不能写时复制
+        space.remove_area_with_start_vpn(VirtAddr::from(SHARED_PAGE).floor())?;
+        let shared = map_shared(&mut space)?;
+        unsafe {
+            let (parent, child) = (inner.shared.as_ref(), shared.as_ptr());
+            (*child).priority = parent.priority;
+            let entry = parent.preempt_entry.load(Ordering::Acquire);
+            (*child).preempt_entry.store(entry, Ordering::Release);
+        }
+        let (stack, ctx) = map_stack(&mut space)?;
+        unsafe {
+            ctx.as_ptr().write(*inner.ctx.as_ref());
+            (*ctx.as_ptr()).a[0] = 0;
+        }
+        let portal = PORTAL_POOL.lock().get(hartid).expect("no portal for this hart");
+        if let Err(err) = space.bind_portal(portal) {
+            dealloc_stack(stack);
+            return Err(err);
+        }
+        let pid = ProcId::new();
+        let child = Arc::new(Self {
+            pid,
+            inner: Mutex::new(ProcessInner {
+                space,
+                stack,
+                ctx,
+                shared,
+                priority: inner.priority,
+                state: ProcessState::Prepared(hartid),
+                entry: inner.entry,
+                user_stack_top: inner.user_stack_top,
+                parent: None,
+                children: Vec::new(),
+                exit_code: 0,
+                waiters: Vec::new(),
+                killed: false,
+            }),
+        });
+        drop(inner);
+        PROCESSES.lock().insert(pid, Arc::downgrade(&child));
+        ALIVE.fetch_add(1, Ordering::AcqRel);
+        self.add_child(child.clone());
+        Ok(child)
+    }
+}
+
+/// 映射并初始化共享页，返回共享页在内核地址空间中的位置
+fn map_shared(space: &mut MemorySet) -> VmResult<NonNull<SharedData>> {
+    space.insert_framed_area(
+        SHARED_PAGE.into(),
+        (SHARED_PAGE + PAGE_SIZE).into(),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    )?;
+    let shared_ppn = space
+        .translate(VirtAddr::from(SHARED_PAGE).floor())
+        .ok_or(VmError::NotMapped)?
+        .ppn();
+    let shared = NonNull::from(shared_ppn.get_mut::<SharedData>());
+    unsafe { shared.as_ptr().write(SharedData::new()) };
+    Ok(shared)
+}
+
+/// 分配进程的栈并映射到高位地址，返回栈和栈顶的上下文，映射失败时回收栈
+///
+/// 栈中保存着上下文，快速路径也在这里执行，用户态不能访问
+fn map_stack(space: &mut MemorySet) -> VmResult<(NonNull<Stack>, NonNull<FlowContext>)> {
+    let stack = alloc_stack().ok_or(VmError::OutOfMemory)?;
+    if let Err(err) = space.map_stack(
+        stack.as_ptr() as *mut usize as usize,
+        MapPermission::R | MapPermission::W,
+    ) {
+        dealloc_stack(stack);
+        return Err(err);
+    }
+    let ctx = unsafe { stack.as_ref().context() };
+    Ok((stack, ctx))
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // 没有结束就被释放的进程不再计入
+        if self.inner.get_mut().state != ProcessState::Zombie {
+            ALIVE.fetch_sub(1, Ordering::AcqRel);
+        }
+        // 先移出进程表再回收编号，复用编号的新进程不会被移除
+        PROCESSES.lock().remove(&self.pid);
+        self.pid.dealloc();
+    }
+}
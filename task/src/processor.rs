@@ -0,0 +1,30 @@
+use alloc::sync::Arc;
+use config::MAX_HART_NUM;
+use spin::Mutex;
+use super::Process;
+
+/// 每个核上正在运行的进程
+static CURRENT: [Mutex<Option<Arc<Process>>>; MAX_HART_NUM] = {
+    const NONE: Mutex<Option<Arc<Process>>> = Mutex::new(None);
+    [NONE; MAX_HART_NUM]
+};
+
+/// 当前核的编号
+///
+/// 内核中的 tp 寄存器保存核的编号，由 `trap_entry` 在进入内核时恢复
+#[inline]
+pub fn hart_id() -> usize {
+    let hartid: usize;
+    unsafe { core::arch::asm!("mv {}, tp", out(reg) hartid) };
+    hartid
+}
+
+/// 获取当前核上正在运行的进程
+pub fn current() -> Option<Arc<Process>> {
+    CURRENT[hart_id()].lock().clone()
+}
+
+/// 设置当前核上正在运行的进程，返回之前的进程
+pub fn set_current(process: Option<Arc<Process>>) -> Option<Arc<Process>> {
+    core::mem::replace(&mut *CURRENT[hart_id()].lock(), process)
+}
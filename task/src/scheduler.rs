@@ -0,0 +1,101 @@
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc};
+use spin::Mutex;
+use super::Process;
+
+/// 调度器，决定下一个运行的进程
+pub trait Scheduler: Send {
+    /// 添加一个就绪的进程
+    fn add(&mut self, process: Arc<Process>);
+    /// 取出下一个要运行的进程
+    fn fetch(&mut self) -> Option<Arc<Process>>;
+}
+
+/// 先进先出的轮转调度器
+#[derive(Default)]
+pub struct FifoScheduler {
+    ready: VecDeque<Arc<Process>>,
+}
+
+impl Scheduler for FifoScheduler {
+    fn add(&mut self, process: Arc<Process>) {
+        self.ready.push_back(process);
+    }
+
+    fn fetch(&mut self) -> Option<Arc<Process>> {
+        self.ready.pop_front()
+    }
+}
+
+/// 就绪进程连续被跳过这么多次之后，无论优先级都会被选中
+const STARVATION_LIMIT: usize = 16;
+
+/// 优先级调度器中等待的进程
+struct Waiting {
+    process: Arc<Process>,
+    priority: usize,
+    /// 被优先级更高的进程跳过的次数
+    skipped: usize,
+}
+
+/// 优先级调度器，选择报告了最紧急协程的进程
+///
+/// 优先级相同的进程按照先进先出的顺序调度，
+/// 等待过久的进程会被优先选中，防止饥饿
+#[derive(Default)]
+pub struct PriorityScheduler {
+    ready: VecDeque<Waiting>,
+}
+
+impl Scheduler for PriorityScheduler {
+    fn add(&mut self, process: Arc<Process>) {
+        let priority = process.inner.lock().priority;
+        self.ready.push_back(Waiting { process, priority, skipped: 0 });
+    }
+
+    fn fetch(&mut self) -> Option<Arc<Process>> {
+        // 先进先出，饥饿的进程一定排在前面
+        let idx = match self.ready.iter().position(|w| w.skipped >= STARVATION_LIMIT) {
+            Some(idx) => idx,
+            None => self
+                .ready
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, w)| w.priority)
+                .map(|(idx, _)| idx)?,
+        };
+        let chosen = self.ready.remove(idx)?;
+        for waiting in self.ready.iter_mut().take(idx) {
+            waiting.skipped += 1;
+        }
+        Some(chosen.process)
+    }
+}
+
+/// 全局的调度器，没有设置时使用 [`FifoScheduler`]
+static SCHEDULER: Mutex<Option<Box<dyn Scheduler>>> = Mutex::new(None);
+
+fn with_scheduler<T>(f: impl FnOnce(&mut dyn Scheduler) -> T) -> T {
+    let mut scheduler = SCHEDULER.lock();
+    f(scheduler.get_or_insert_with(|| Box::new(FifoScheduler::default())).as_mut())
+}
+
+/// 替换全局的调度器，已经就绪的进程会转移到新的调度器中
+pub fn set_scheduler(mut scheduler: Box<dyn Scheduler>) {
+    let mut global = SCHEDULER.lock();
+    if let Some(old) = global.as_mut() {
+        while let Some(process) = old.fetch() {
+            scheduler.add(process);
+        }
+    }
+    *global = Some(scheduler);
+}
+
+/// 添加就绪的进程
+pub fn add_process(process: Arc<Process>) {
+    with_scheduler(|scheduler| scheduler.add(process));
+}
+
+/// 取出下一个要运行的进程
+pub fn fetch_process() -> Option<Arc<Process>> {
+    with_scheduler(|scheduler| scheduler.fetch())
+}
@@ -0,0 +1,350 @@
+use core::sync::atomic::Ordering;
+use core::task::{Context, RawWaker, RawWakerVTable, Waker};
+use config::EXECUTOR_BASE;
+use syscall::SyscallId;
+use crate::{heap::Heap, set_priority, thread::{Threads, yield_thread}, PollFn, SharedData, PRIORITY_NUM};
+
+/// 执行器中最多同时存在的协程数量
+pub const MAX_COROUTINES: usize = 64;
+
+/// 协程执行的内容
+#[derive(Clone, Copy)]
+enum Body {
+    /// 空闲的槽
+    Empty,
+    /// 用户程序中的 future
+    Future { future: *mut (), poll: PollFn },
+    /// 同步的函数，轮询一次就完成，main 协程就是这样
+    Function { entry: extern "C" fn(usize), arg: usize },
+}
+
+#[derive(Clone, Copy)]
+struct Coroutine {
+    body: Body,
+    priority: usize,
+    /// 被唤醒之后等待轮询
+    ready: bool,
+}
+
+/// 协程执行器
+///
+/// vdso 中不能有可变的全局变量，执行器位于用户地址空间中的 `EXECUTOR_BASE`，
+/// 由内核在创建进程时映射
+pub struct Executor {
+    coroutines: [Coroutine; MAX_COROUTINES],
+    /// 运行时构造的虚表，其中的函数地址位于当前的 vDSO 槽中
+    vtable: RawWakerVTable,
+    /// 运行执行器的线程
+    threads: Threads,
+    /// 用户堆
+    heap: Heap,
+    /// 正在取出或者收回协程，此时不能抢占线程，否则同一个协程可能被两个线程轮询
+    busy: bool,
+}
+
+const _: () = assert!(core::mem::size_of::<Executor>() <= config::EXECUTOR_SIZE);
+// 内核通过一个 usize 的位图唤醒协程
+const _: () = assert!(MAX_COROUTINES <= usize::BITS as usize);
+
+/// 执行器的函数都在 vDSO 槽中执行，需要内联，不能调用 `.text` 中的函数
+impl Executor {
+    #[inline(always)]
+    fn get() -> &'static mut Self {
+        unsafe { &mut *(EXECUTOR_BASE as *mut Self) }
+    }
+
+    #[inline(always)]
+    fn add(&mut self, body: Body, priority: usize) -> usize {
+        let priority = if priority < PRIORITY_NUM { priority } else { PRIORITY_NUM - 1 };
+        let mut id = 0;
+        while id < MAX_COROUTINES {
+            let coroutine = &mut self.coroutines[id];
+            if let Body::Empty = coroutine.body {
+                *coroutine = Coroutine { body, priority, ready: true };
+                self.report();
+                return id;
+            }
+            id += 1;
+        }
+        usize::MAX
+    }
+
+    /// 最紧急的就绪协程
+    #[inline(always)]
+    fn fetch(&self) -> Option<usize> {
+        let mut chosen = None;
+        let mut id = 0;
+        while id < MAX_COROUTINES {
+            let coroutine = &self.coroutines[id];
+            let better = match chosen {
+                None => true,
+                Some(c) => coroutine.priority < self.coroutines[c].priority,
+            };
+            if coroutine.ready && !matches!(coroutine.body, Body::Empty) && better {
+                chosen = Some(id);
+            }
+            id += 1;
+        }
+        chosen
+    }
+
+    /// 是否还有没完成的协程
+    #[inline(always)]
+    fn is_empty(&self) -> bool {
+        let mut id = 0;
+        while id < MAX_COROUTINES {
+            if !matches!(self.coroutines[id].body, Body::Empty) {
+                return false;
+            }
+            id += 1;
+        }
+        true
+    }
+
+    /// 处理内核通过共享页唤醒的协程
+    #[inline(always)]
+    fn apply_pending(&mut self) {
+        let pending = SharedData::user().pending.swap(0, Ordering::Acquire);
+        if pending == 0 {
+            return;
+        }
+        let mut id = 0;
+        while id < MAX_COROUTINES {
+            if pending & (1 << id) != 0 {
+                self.coroutines[id].ready = true;
+            }
+            id += 1;
+        }
+        self.report();
+    }
+
+    /// 通过共享页向内核报告最紧急的就绪协程的优先级
+    #[inline(always)]
+    fn report(&self) {
+        let priority = match self.fetch() {
+            Some(id) => self.coroutines[id].priority,
+            None => PRIORITY_NUM - 1,
+        };
+        set_priority(priority);
+    }
+}
+
+/// 执行器中的线程表
+#[inline(always)]
+pub(crate) fn threads() -> &'static mut Threads {
+    &mut Executor::get().threads
+}
+
+/// 执行器中的用户堆
+#[inline(always)]
+pub(crate) fn heap() -> &'static mut Heap {
+    &mut Executor::get().heap
+}
+
+/// 是否有就绪的协程
+#[inline(always)]
+pub(crate) fn has_ready() -> bool {
+    Executor::get().fetch().is_some()
+}
+
+#[link_section = ".text.vdso"]
+unsafe fn waker_clone(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &Executor::get().vtable)
+}
+
+#[link_section = ".text.vdso"]
+unsafe fn waker_wake(data: *const ()) {
+    let executor = Executor::get();
+    let id = data as usize;
+    if id < MAX_COROUTINES {
+        executor.coroutines[id].ready = true;
+        executor.report();
+    }
+}
+
+#[link_section = ".text.vdso"]
+unsafe fn waker_drop(_data: *const ()) {}
+
+/// 初始化执行器
+#[link_section = ".text.vdso"]
+pub extern "C" fn init() {
+    let executor = Executor::get();
+    let mut id = 0;
+    while id < MAX_COROUTINES {
+        executor.coroutines[id] = Coroutine { body: Body::Empty, priority: PRIORITY_NUM - 1, ready: false };
+        id += 1;
+    }
+    // 取函数地址使用相对寻址，得到的是当前 vDSO 槽中的地址
+    executor.vtable = RawWakerVTable::new(waker_clone, waker_wake, waker_wake, waker_drop);
+    executor.threads.init();
+    executor.busy = false;
+}
+
+/// 添加一个协程，返回协程的编号，执行器已满时返回 `usize::MAX`
+#[link_section = ".text.vdso"]
+pub extern "C" fn add_coroutine(future: *mut (), poll: PollFn, priority: usize) -> usize {
+    Executor::get().add(Body::Future { future, poll }, priority)
+}
+
+/// 把同步的函数作为协程添加到执行器中，`entry(arg)` 返回之后协程就完成了
+#[link_section = ".text.vdso"]
+pub extern "C" fn spawn(entry: extern "C" fn(usize), arg: usize, priority: usize) -> usize {
+    Executor::get().add(Body::Function { entry, arg }, priority)
+}
+
+/// 不断地取出最紧急的就绪协程执行，所有协程完成之后返回
+///
+/// 每个线程都运行这个循环，一个协程阻塞了所在的线程时，其他线程继续执行剩下的协程
+#[link_section = ".text.vdso"]
+pub extern "C" fn run() {
+    let executor = Executor::get();
+    loop {
+        executor.busy = true;
+        executor.apply_pending();
+        let id = match executor.fetch() {
+            Some(id) => id,
+            None if executor.is_empty() => {
+                executor.busy = false;
+                return;
+            }
+            // 协程都在等待，先切换到其他线程，都在等待时让出处理器等待唤醒
+            None => {
+                executor.busy = false;
+                if executor.threads.has_ready() {
+                    yield_thread();
+                } else {
+                    sched_yield();
+                }
+                continue;
+            }
+        };
+        executor.coroutines[id].ready = false;
+        executor.threads.set_running(executor.coroutines[id].priority);
+        executor.busy = false;
+        let finished = match executor.coroutines[id].body {
+            Body::Future { future, poll } => {
+                let waker = unsafe { Waker::from_raw(RawWaker::new(id as *const (), &executor.vtable)) };
+                let mut cx = Context::from_waker(&waker);
+                poll(future, &mut cx)
+            }
+            Body::Function { entry, arg } => {
+                entry(arg);
+                true
+            }
+            Body::Empty => true,
+        };
+        executor.busy = true;
+        executor.threads.set_running(PRIORITY_NUM);
+        if finished {
+            executor.coroutines[id].body = Body::Empty;
+        }
+        executor.report();
+        executor.busy = false;
+    }
+}
+
+/// 陷入返回时发现内核唤醒了协程，快速路径把被打断的上下文保存在用户栈上，然后跳转到这里
+///
+/// 先补全快速路径中没有保存的寄存器，被唤醒的协程更紧急时抢占当前线程，
+/// 再次切换回这个线程之后通过 `preempt_return` 恢复被打断的上下文。
+/// 上下文的布局与 `fast_trap::FlowContext` 相同
+#[naked]
+#[link_section = ".text.vdso"]
+pub unsafe extern "C" fn preempt_entry(_frame: usize) -> ! {
+    core::arch::asm!(
+        "
+            sd s0, 16*8(a0)
+            sd s1, 17*8(a0)
+            sd s2, 18*8(a0)
+            sd s3, 19*8(a0)
+            sd s4, 20*8(a0)
+            sd s5, 21*8(a0)
+            sd s6, 22*8(a0)
+            sd s7, 23*8(a0)
+            sd s8, 24*8(a0)
+            sd s9, 25*8(a0)
+            sd s10, 26*8(a0)
+            sd s11, 27*8(a0)
+            sd gp, 28*8(a0)
+            sd tp, 29*8(a0)
+            mv s0, a0
+            call {preempt}
+            mv a0, s0
+            ld s0, 16*8(a0)
+            ld s1, 17*8(a0)
+            ld s2, 18*8(a0)
+            ld s3, 19*8(a0)
+            ld s4, 20*8(a0)
+            ld s5, 21*8(a0)
+            ld s6, 22*8(a0)
+            ld s7, 23*8(a0)
+            ld s8, 24*8(a0)
+            ld s9, 25*8(a0)
+            ld s10, 26*8(a0)
+            ld s11, 27*8(a0)
+            ld gp, 28*8(a0)
+            ld tp, 29*8(a0)
+            li a7, {preempt_return}
+            ecall
+        ",
+        preempt = sym preempt,
+        preempt_return = const SyscallId::preempt_return as usize,
+        options(noreturn)
+    )
+}
+
+/// 被唤醒的协程比当前线程正在执行的更紧急时，切换到其他线程执行它
+#[link_section = ".text.vdso"]
+extern "C" fn preempt() {
+    let executor = Executor::get();
+    executor.apply_pending();
+    // 被打断的线程正在修改空闲链表或者正在取出协程，不能切换到其他线程
+    if executor.heap.is_busy() || executor.busy {
+        return;
+    }
+    if let Some(id) = executor.fetch() {
+        let priority = executor.coroutines[id].priority;
+        if priority < executor.threads.running() {
+            executor.threads.preempt(priority);
+        }
+    }
+}
+
+/// vdso 不能调用 syscall 中的用户态函数，直接发起系统调用
+#[inline(always)]
+pub(crate) fn sched_yield() {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SyscallId::sched_yield as usize,
+            lateout("a0") _,
+        )
+    };
+}
+
+/// 移动程序断点，返回原来的程序断点，失败时返回负数
+#[inline(always)]
+pub(crate) fn sbrk(increment: isize) -> isize {
+    let ret: isize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SyscallId::sbrk as usize,
+            inlateout("a0") increment => ret,
+        )
+    };
+    ret
+}
+
+/// 结束进程
+#[inline(always)]
+pub(crate) fn exit(exit_code: i32) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            in("a7") SyscallId::exit as usize,
+            in("a0") exit_code,
+            options(noreturn),
+        )
+    };
+}
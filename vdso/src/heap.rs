@@ -0,0 +1,167 @@
+//! 用户堆
+//!
+//! 堆的内存通过 sbrk 向内核申请，空闲块按地址排序串成链表，分配时首次适配，释放时合并相邻的空闲块。
+//! 堆的状态位于执行器的内存中，和执行器一样只能使用内联的函数。
+
+use core::ptr::null_mut;
+use config::PAGE_SIZE;
+use crate::executor::{heap, sbrk};
+
+/// 块的对齐，也是块头的大小
+const ALIGN: usize = 16;
+/// 初始化以及每次增长时至少向内核申请的大小
+const GROW_SIZE: usize = 4 * PAGE_SIZE;
+
+/// 块头，已经分配的块只使用 `size`
+#[repr(C)]
+struct Block {
+    /// 包括块头在内的大小
+    size: usize,
+    /// 下一个空闲块，0 表示没有
+    next: usize,
+}
+
+#[inline(always)]
+fn block(addr: usize) -> &'static mut Block {
+    unsafe { &mut *(addr as *mut Block) }
+}
+
+/// 堆的状态
+pub struct Heap {
+    /// 地址最小的空闲块，0 表示没有
+    free: usize,
+    /// 正在分配或者释放，此时不能抢占线程
+    busy: bool,
+}
+
+impl Heap {
+    /// 正在修改空闲链表
+    #[inline(always)]
+    pub fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    /// 向内核申请至少 `size` 字节加入空闲链表
+    #[inline(always)]
+    fn grow(&mut self, size: usize) -> bool {
+        let size = (size + GROW_SIZE - 1) / GROW_SIZE * GROW_SIZE;
+        let start = sbrk(size as isize);
+        if start < 0 {
+            return false;
+        }
+        // 用户程序也可以调用 sbrk，程序断点不一定对齐
+        let start = start as usize;
+        let aligned = (start + ALIGN - 1) & !(ALIGN - 1);
+        self.insert(aligned, (size - (aligned - start)) & !(ALIGN - 1));
+        true
+    }
+
+    /// 把 `addr` 开始的 `size` 字节按地址顺序插入空闲链表，和相邻的空闲块合并
+    #[inline(always)]
+    fn insert(&mut self, addr: usize, size: usize) {
+        let mut prev = 0;
+        let mut next = self.free;
+        while next != 0 && next < addr {
+            prev = next;
+            next = block(next).next;
+        }
+        let current = block(addr);
+        current.size = size;
+        current.next = next;
+        if next != 0 && addr + size == next {
+            current.size += block(next).size;
+            current.next = block(next).next;
+        }
+        if prev == 0 {
+            self.free = addr;
+            return;
+        }
+        let prev_block = block(prev);
+        if prev + prev_block.size == addr {
+            prev_block.size += current.size;
+            prev_block.next = current.next;
+        } else {
+            prev_block.next = addr;
+        }
+    }
+
+    /// 从空闲链表中取出 `need` 字节的块，返回块的地址，没有足够大的块时返回 0
+    #[inline(always)]
+    fn take(&mut self, need: usize) -> usize {
+        let mut prev = 0;
+        let mut current = self.free;
+        while current != 0 {
+            let free = block(current);
+            if free.size >= need {
+                // 剩下的部分还能放下一个块时，从空闲块的末尾切出
+                if free.size - need >= 2 * ALIGN {
+                    free.size -= need;
+                    let addr = current + free.size;
+                    block(addr).size = need;
+                    return addr;
+                }
+                if prev == 0 {
+                    self.free = free.next;
+                } else {
+                    block(prev).next = free.next;
+                }
+                return current;
+            }
+            prev = current;
+            current = free.next;
+        }
+        0
+    }
+
+    #[inline(always)]
+    fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        if align > ALIGN || size > isize::MAX as usize - GROW_SIZE {
+            return null_mut();
+        }
+        let need = ((size + ALIGN - 1) & !(ALIGN - 1)) + ALIGN;
+        self.busy = true;
+        let mut addr = self.take(need);
+        // 对齐程序断点可能损失不到一个块头
+        if addr == 0 && self.grow(need + ALIGN) {
+            addr = self.take(need);
+        }
+        self.busy = false;
+        if addr == 0 {
+            null_mut()
+        } else {
+            (addr + ALIGN) as *mut u8
+        }
+    }
+
+    #[inline(always)]
+    fn dealloc(&mut self, ptr: *mut u8) {
+        if ptr.is_null() {
+            return;
+        }
+        let addr = ptr as usize - ALIGN;
+        self.busy = true;
+        self.insert(addr, block(addr).size);
+        self.busy = false;
+    }
+}
+
+/// 初始化用户堆，预先向内核申请一部分内存
+#[inline(always)]
+pub(crate) fn init() {
+    let heap = heap();
+    heap.free = 0;
+    heap.busy = false;
+    heap.grow(GROW_SIZE);
+}
+
+/// 分配 `size` 字节，`align` 不能超过 16，失败时返回空指针
+#[link_section = ".text.vdso"]
+pub extern "C" fn alloc(size: usize, align: usize) -> *mut u8 {
+    heap().alloc(size, align)
+}
+
+/// 释放 [`alloc`] 分配的内存
+#[link_section = ".text.vdso"]
+pub extern "C" fn dealloc(ptr: *mut u8) {
+    heap().dealloc(ptr)
+}
@@ -1,10 +1,100 @@
 #![no_std]
+#![feature(naked_functions, asm_const)]
 
+mod executor;
+mod heap;
+mod shared;
+mod thread;
+
+pub use executor::{MAX_COROUTINES, add_coroutine, spawn, run};
+pub use heap::{alloc, dealloc};
+pub use vdso_user::PollFn;
+pub use shared::{SharedData, PRIORITY_NUM, set_priority};
+pub use thread::{spawn_thread, yield_thread};
+
+use config::vdso_va;
+use linker::locate_vdso;
+use vdso_user::{VdsoTable, VDSO_MAGIC, VDSO_VERSION, ENTRY_NUM};
+
+/// vdso 段中的函数映射到第 `hartid` 个核的 vDSO 槽之后的地址
+///
+/// vdso 段会被映射到不同的地址，其中的代码只能使用相对寻址，不能调用 `.text` 段中的函数
+#[inline]
+pub fn vdso_addr(hartid: usize, f: usize) -> usize {
+    f - locate_vdso().start + vdso_va(hartid)
+}
+
+/// 用户进程的入口
+///
+/// 内核在上下文中设置 a0 为 ELF 的入口地址，a1 为用户栈顶，
+/// 切换到用户栈之后进入 [`vdso_main`]
+#[naked]
 #[link_section = ".text.vdso"]
-pub unsafe extern "C" fn user_entry() {
+pub unsafe extern "C" fn user_entry() -> ! {
     core::arch::asm!(
         "
-            ecall
+            mv sp, a1
+            j {main}
+        ",
+        main = sym vdso_main,
+        options(noreturn)
+    )
+}
+
+/// 初始化执行器和用户堆，把 ELF 的入口封装成 main 协程，然后不断地执行协程
+///
+/// main 协程的参数为 [`vdso_table`] 的地址，用户程序用它构造 `vdso_user::Vdso`
+#[link_section = ".text.vdso"]
+extern "C" fn vdso_main(entry: usize) -> ! {
+    executor::init();
+    heap::init();
+    // 开启协程抢占
+    let preempt_entry = executor::preempt_entry as usize;
+    SharedData::user().preempt_entry.store(preempt_entry, core::sync::atomic::Ordering::Release);
+    let table: usize;
+    unsafe { core::arch::asm!("lla {}, {table}", out(reg) table, table = sym vdso_table) };
+    let main: extern "C" fn(usize) = unsafe { core::mem::transmute(entry) };
+    executor::spawn(main, table, 0);
+    executor::run();
+    // 所有的协程都已经完成
+    executor::exit(0)
+}
+
+/// vDSO 的函数表，布局为 [`vdso_user::VdsoTable`]
+///
+/// 链接脚本把它放在 vdso 段的起始位置，用户程序通过 `vdso_user` 按编号调用其中的函数。
+/// 表中记录的是相对于函数表的偏移，在任何 vDSO 槽中都可以使用
+#[naked]
+#[link_section = ".text.vdso.table"]
+pub unsafe extern "C" fn vdso_table() {
+    core::arch::asm!(
         "
+        1:
+            .word {magic}
+            .word {version}
+            .dword {len}
+            .dword {add_coroutine} - 1b
+            .dword {spawn} - 1b
+            .dword {run} - 1b
+            .dword {set_priority} - 1b
+            .dword {yield_thread} - 1b
+            .dword {spawn_thread} - 1b
+            .dword {alloc} - 1b
+            .dword {dealloc} - 1b
+        ",
+        magic = const VDSO_MAGIC,
+        version = const VDSO_VERSION,
+        len = const ENTRY_NUM,
+        add_coroutine = sym add_coroutine,
+        spawn = sym spawn,
+        run = sym run,
+        set_priority = sym set_priority,
+        yield_thread = sym yield_thread,
+        spawn_thread = sym spawn_thread,
+        alloc = sym alloc,
+        dealloc = sym dealloc,
+        options(noreturn)
     )
-}
\ No newline at end of file
+}
+
+const _: () = assert!(core::mem::size_of::<VdsoTable>() == 16 + 8 * ENTRY_NUM);
@@ -0,0 +1,42 @@
+use core::sync::atomic::AtomicUsize;
+use config::SHARED_PAGE;
+
+/// 优先级的数量，0 最紧急
+pub const PRIORITY_NUM: usize = 8;
+
+/// 用户进程与内核共享的数据，位于 `SHARED_PAGE`
+///
+/// 用户态的执行器写入，内核在进程每次陷入时读取
+#[repr(C)]
+pub struct SharedData {
+    /// 进程中最紧急的就绪协程的优先级
+    pub priority: usize,
+    /// 内核唤醒的协程，每一位对应执行器中的一个协程，由内核置位
+    pub pending: AtomicUsize,
+    /// vDSO 中处理抢占的入口，为 0 时不会抢占
+    pub preempt_entry: AtomicUsize,
+}
+
+impl SharedData {
+    /// 进程创建时优先级初始化为最高
+    pub const fn new() -> Self {
+        Self { priority: 0, pending: AtomicUsize::new(0), preempt_entry: AtomicUsize::new(0) }
+    }
+
+    /// 共享页在用户地址空间中的位置
+    #[inline(always)]
+    pub fn user() -> &'static Self {
+        unsafe { &*(SHARED_PAGE as *const Self) }
+    }
+}
+
+/// 向内核报告进程中最紧急的就绪协程的优先级
+///
+/// 只写共享页，不会陷入内核
+#[link_section = ".text.vdso"]
+pub extern "C" fn set_priority(priority: usize) {
+    // 不能调用 `Ord::min`，它可能不会被内联
+    let priority = if priority < PRIORITY_NUM { priority } else { PRIORITY_NUM - 1 };
+    let shared = SHARED_PAGE as *mut SharedData;
+    unsafe { core::ptr::write_volatile(core::ptr::addr_of_mut!((*shared).priority), priority) };
+}
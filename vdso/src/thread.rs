@@ -0,0 +1,215 @@
+//! 用户态线程
+//!
+//! 线程是栈的承载者，每个线程都在自己的栈上运行执行器，线程切换只保存被调用者保存的寄存器，不进入内核。
+//! 0 号线程是进程的主线程，使用内核分配的用户栈，其他线程的栈位于 `THREAD_STACK_BASE`。
+//! 内核只看到一个进程上下文，哪个线程陷入内核，它的寄存器就保存在这个上下文中，
+//! 相当于替换了主线程的上下文，从内核返回之后继续执行这个线程。
+
+use config::{MAX_THREADS, THREAD_STACK_BASE, THREAD_STACK_SIZE};
+use crate::PRIORITY_NUM;
+
+/// 线程切换时保存的寄存器
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ThreadContext {
+    ra: usize,
+    sp: usize,
+    s: [usize; 12],
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ThreadState {
+    /// 空闲的槽
+    Free,
+    /// 等待切换
+    Ready,
+    /// 正在运行
+    Running,
+}
+
+/// 线程表，位于执行器的内存中
+pub struct Threads {
+    contexts: [ThreadContext; MAX_THREADS],
+    states: [ThreadState; MAX_THREADS],
+    /// 线程正在执行的协程的优先级，没有执行协程时为 `PRIORITY_NUM`
+    running: [usize; MAX_THREADS],
+    current: usize,
+}
+
+impl Threads {
+    /// 只有正在运行的主线程
+    #[inline(always)]
+    pub fn init(&mut self) {
+        let mut id = 0;
+        while id < MAX_THREADS {
+            self.contexts[id] = ThreadContext { ra: 0, sp: 0, s: [0; 12] };
+            self.states[id] = ThreadState::Free;
+            self.running[id] = PRIORITY_NUM;
+            id += 1;
+        }
+        self.states[0] = ThreadState::Running;
+        self.current = 0;
+    }
+
+    /// 当前线程之后第一个等待切换的线程
+    #[inline(always)]
+    fn next_ready(&self) -> Option<usize> {
+        let mut i = 1;
+        while i < MAX_THREADS {
+            let id = (self.current + i) % MAX_THREADS;
+            if self.states[id] == ThreadState::Ready {
+                return Some(id);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// 是否有其他等待切换的线程
+    #[inline(always)]
+    pub fn has_ready(&self) -> bool {
+        self.next_ready().is_some()
+    }
+
+    /// 创建一个在新的栈上运行执行器的线程，没有空闲的槽时返回 `usize::MAX`
+    #[inline(always)]
+    pub fn spawn(&mut self) -> usize {
+        let mut id = 1;
+        while id < MAX_THREADS {
+            if self.states[id] == ThreadState::Free {
+                let stack_top = THREAD_STACK_BASE + id * THREAD_STACK_SIZE;
+                self.contexts[id] = ThreadContext { ra: thread_entry as usize, sp: stack_top, s: [0; 12] };
+                self.states[id] = ThreadState::Ready;
+                return id;
+            }
+            id += 1;
+        }
+        usize::MAX
+    }
+
+    /// 记录当前线程正在执行的协程的优先级
+    #[inline(always)]
+    pub fn set_running(&mut self, priority: usize) {
+        self.running[self.current] = priority;
+    }
+
+    /// 当前线程正在执行的协程的优先级
+    #[inline(always)]
+    pub fn running(&self) -> usize {
+        self.running[self.current]
+    }
+
+    /// 切换到下一个等待切换的线程，`exit` 为真时当前线程的槽被回收
+    #[inline(always)]
+    fn switch_next(&mut self, exit: bool) -> bool {
+        match self.next_ready() {
+            Some(next) => {
+                self.switch_to(next, exit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 为优先级为 `priority` 的协程抢占当前线程
+    ///
+    /// 切换到一个空闲的或者执行着更紧急的协程的线程，没有的话创建新的线程
+    #[inline(always)]
+    pub fn preempt(&mut self, priority: usize) {
+        let mut i = 1;
+        while i < MAX_THREADS {
+            let id = (self.current + i) % MAX_THREADS;
+            let running = self.running[id];
+            if self.states[id] == ThreadState::Ready && (running == PRIORITY_NUM || running <= priority) {
+                self.switch_to(id, false);
+                return;
+            }
+            i += 1;
+        }
+        let id = self.spawn();
+        if id != usize::MAX {
+            self.switch_to(id, false);
+        }
+    }
+
+    #[inline(always)]
+    fn switch_to(&mut self, next: usize, exit: bool) {
+        let current = self.current;
+        self.states[current] = if exit { ThreadState::Free } else { ThreadState::Ready };
+        self.states[next] = ThreadState::Running;
+        self.current = next;
+        let from = &mut self.contexts[current] as *mut ThreadContext;
+        let to = &self.contexts[next] as *const ThreadContext;
+        unsafe { switch(from, to) };
+    }
+}
+
+/// 新线程的入口，执行器中的协程都完成之后线程退出
+#[link_section = ".text.vdso"]
+extern "C" fn thread_entry() -> ! {
+    crate::executor::run();
+    let threads = crate::executor::threads();
+    loop {
+        // 主线程不会退出，总能切换出去
+        threads.switch_next(true);
+    }
+}
+
+/// 保存当前线程的被调用者保存寄存器，恢复另一个线程的
+#[naked]
+#[link_section = ".text.vdso"]
+unsafe extern "C" fn switch(_from: *mut ThreadContext, _to: *const ThreadContext) {
+    core::arch::asm!(
+        "
+            sd ra, 0*8(a0)
+            sd sp, 1*8(a0)
+            sd s0, 2*8(a0)
+            sd s1, 3*8(a0)
+            sd s2, 4*8(a0)
+            sd s3, 5*8(a0)
+            sd s4, 6*8(a0)
+            sd s5, 7*8(a0)
+            sd s6, 8*8(a0)
+            sd s7, 9*8(a0)
+            sd s8, 10*8(a0)
+            sd s9, 11*8(a0)
+            sd s10, 12*8(a0)
+            sd s11, 13*8(a0)
+            ld ra, 0*8(a1)
+            ld sp, 1*8(a1)
+            ld s0, 2*8(a1)
+            ld s1, 3*8(a1)
+            ld s2, 4*8(a1)
+            ld s3, 5*8(a1)
+            ld s4, 6*8(a1)
+            ld s5, 7*8(a1)
+            ld s6, 8*8(a1)
+            ld s7, 9*8(a1)
+            ld s8, 10*8(a1)
+            ld s9, 11*8(a1)
+            ld s10, 12*8(a1)
+            ld s11, 13*8(a1)
+            ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// 创建一个在新的栈上运行执行器的线程，返回线程的编号，没有空闲的槽时返回 `usize::MAX`
+#[link_section = ".text.vdso"]
+pub extern "C" fn spawn_thread() -> usize {
+    crate::executor::threads().spawn()
+}
+
+/// 当前线程让出处理器
+///
+/// 当前线程被同步操作阻塞时调用。没有其他线程但是还有就绪的协程时，
+/// 先创建新的线程来执行这些协程，这样进程不会因为一个协程而停下来
+#[link_section = ".text.vdso"]
+pub extern "C" fn yield_thread() {
+    let threads = crate::executor::threads();
+    if !threads.has_ready() && crate::executor::has_ready() {
+        threads.spawn();
+    }
+    threads.switch_next(false);
+}
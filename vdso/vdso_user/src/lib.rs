@@ -0,0 +1,160 @@
+//! vDSO 的函数表，用户程序通过这个 crate 调用 vDSO 中的函数
+//!
+//! 函数表位于 vDSO 槽的起始位置，每一项记录函数相对于函数表的偏移，
+//! 新的函数只会追加在末尾，已有的编号不会改变，因此 vDSO 更新之后用户程序不需要重新链接
+
+#![no_std]
+#![deny(warnings, missing_docs)]
+
+use core::task::Context;
+
+/// 函数表的魔数，"VDSO"
+pub const VDSO_MAGIC: u32 = u32::from_le_bytes(*b"VDSO");
+/// 函数表的版本，增加函数之后加一
+pub const VDSO_VERSION: u32 = 3;
+
+/// 函数表中的编号
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    /// `extern "C" fn(future: *mut (), poll: PollFn, priority: usize) -> usize`
+    AddCoroutine = 0,
+    /// `extern "C" fn(entry: extern "C" fn(usize), arg: usize, priority: usize) -> usize`
+    Spawn = 1,
+    /// `extern "C" fn()`
+    Run = 2,
+    /// `extern "C" fn(priority: usize)`
+    SetPriority = 3,
+    /// `extern "C" fn()`，版本 2 加入
+    YieldThread = 4,
+    /// `extern "C" fn() -> usize`，版本 2 加入
+    SpawnThread = 5,
+    /// `extern "C" fn(size: usize, align: usize) -> *mut u8`，版本 3 加入
+    Alloc = 6,
+    /// `extern "C" fn(ptr: *mut u8)`，版本 3 加入
+    Dealloc = 7,
+}
+
+/// 当前版本的函数数量
+pub const ENTRY_NUM: usize = 8;
+
+/// 轮询用户程序中的 future，返回 `true` 表示已经完成
+pub type PollFn = extern "C" fn(future: *mut (), cx: *mut Context<'_>) -> bool;
+
+/// vDSO 槽起始位置的函数表
+#[repr(C)]
+pub struct VdsoTable {
+    /// 固定为 [`VDSO_MAGIC`]
+    pub magic: u32,
+    /// 生成函数表的 vDSO 的版本
+    pub version: u32,
+    /// 函数的数量
+    pub len: usize,
+    /// 函数相对于函数表的偏移，实际的长度为 `len`
+    pub entries: [usize; ENTRY_NUM],
+}
+
+/// 解析函数表的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VdsoError {
+    /// 地址处不是函数表
+    BadMagic,
+}
+
+/// 用户程序看到的 vDSO
+#[derive(Clone, Copy)]
+pub struct Vdso {
+    table: &'static VdsoTable,
+}
+
+impl Vdso {
+    /// 从函数表的地址解析 vDSO，main 协程的参数就是这个地址
+    ///
+    /// # Safety
+    ///
+    /// `addr` 需要是映射在用户地址空间中的 vDSO 槽
+    pub unsafe fn from_table(addr: usize) -> Result<Self, VdsoError> {
+        let table = &*(addr as *const VdsoTable);
+        if table.magic != VDSO_MAGIC {
+            return Err(VdsoError::BadMagic);
+        }
+        Ok(Self { table })
+    }
+
+    /// vDSO 的版本
+    pub fn version(&self) -> u32 {
+        self.table.version
+    }
+
+    /// 按编号找到函数的地址，比用户程序旧的 vDSO 中可能没有这个函数
+    pub fn entry(&self, entry: Entry) -> Option<usize> {
+        let idx = entry as usize;
+        if idx >= self.table.len {
+            return None;
+        }
+        // 函数表可能比这个 crate 知道的更长，越界的项不会被访问
+        let offset = unsafe { *self.table.entries.as_ptr().add(idx) };
+        Some(self.table as *const _ as usize + offset)
+    }
+
+    fn resolve<F: Copy>(&self, entry: Entry) -> F {
+        let addr = self.entry(entry).expect("vdso entry not found");
+        unsafe { core::mem::transmute_copy(&addr) }
+    }
+
+    /// 添加一个协程，返回协程的编号，执行器已满时返回 `usize::MAX`
+    ///
+    /// # Safety
+    ///
+    /// `future` 在协程完成之前需要保持有效
+    pub unsafe fn add_coroutine(&self, future: *mut (), poll: PollFn, priority: usize) -> usize {
+        let f: extern "C" fn(*mut (), PollFn, usize) -> usize = self.resolve(Entry::AddCoroutine);
+        f(future, poll, priority)
+    }
+
+    /// 把同步的函数作为协程添加到执行器中
+    pub fn spawn(&self, entry: extern "C" fn(usize), arg: usize, priority: usize) -> usize {
+        let f: extern "C" fn(extern "C" fn(usize), usize, usize) -> usize = self.resolve(Entry::Spawn);
+        f(entry, arg, priority)
+    }
+
+    /// 执行协程，直到所有的协程完成
+    pub fn run(&self) {
+        let f: extern "C" fn() = self.resolve(Entry::Run);
+        f()
+    }
+
+    /// 向内核报告最紧急的就绪协程的优先级
+    pub fn set_priority(&self, priority: usize) {
+        let f: extern "C" fn(usize) = self.resolve(Entry::SetPriority);
+        f(priority)
+    }
+
+    /// 当前线程让出处理器，同步操作阻塞了当前线程时调用
+    pub fn yield_thread(&self) {
+        let f: extern "C" fn() = self.resolve(Entry::YieldThread);
+        f()
+    }
+
+    /// 创建一个运行执行器的线程，返回线程的编号，没有空闲的槽时返回 `usize::MAX`
+    pub fn spawn_thread(&self) -> usize {
+        let f: extern "C" fn() -> usize = self.resolve(Entry::SpawnThread);
+        f()
+    }
+
+    /// 从 vDSO 管理的用户堆中分配 `size` 字节，`align` 不能超过 16，失败时返回空指针
+    pub fn alloc(&self, size: usize, align: usize) -> *mut u8 {
+        let f: extern "C" fn(usize, usize) -> *mut u8 = self.resolve(Entry::Alloc);
+        f(size, align)
+    }
+
+    /// 释放 [`Vdso::alloc`] 分配的内存
+    ///
+    /// # Safety
+    ///
+    /// `ptr` 需要是 [`Vdso::alloc`] 返回的、还没有释放的指针
+    pub unsafe fn dealloc(&self, ptr: *mut u8) {
+        let f: extern "C" fn(*mut u8) = self.resolve(Entry::Dealloc);
+        f(ptr)
+    }
+}
@@ -7,6 +7,14 @@ const VA_WIDTH_SV39: usize = 39;
 const PPN_WIDTH_SV39: usize = PA_WIDTH_SV39 - PAGE_SIZE_BITS;
 const VPN_WIDTH_SV39: usize = VA_WIDTH_SV39 - PAGE_SIZE_BITS;
 
+/// `start..end` 是否都是符号扩展的 Sv39 地址
+///
+/// 其他地址转换成 [`VirtAddr`] 时高位被截断，会落到别的页上
+pub(crate) fn is_canonical(start: usize, end: usize) -> bool {
+    const HALF: usize = 1 << (VA_WIDTH_SV39 - 1);
+    end <= HALF || start >= HALF.wrapping_neg()
+}
+
 /// Definitions
 #[repr(C)]
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
@@ -0,0 +1,23 @@
+/// 地址空间操作的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// 没有空闲的物理页
+    OutOfMemory,
+    /// 页已经被映射
+    AlreadyMapped,
+    /// 页没有被映射
+    NotMapped,
+    /// 不是合法的 ELF 文件
+    BadElf,
+    /// 区域的权限不允许这次访问
+    PermissionDenied,
+    /// 地址不属于任何区域
+    BadAddress,
+    /// 地址没有对齐、长度为 0 或者超出用户地址空间
+    InvalidArgument,
+    /// 和已有的区域重叠
+    Overlapped,
+}
+
+/// 地址空间操作的结果
+pub type VmResult<T> = Result<T, VmError>;
@@ -25,6 +25,13 @@ pub fn stack_alloc() -> Option<usize> {
         .map(|ppn| ppn * PAGE_SIZE)
 }
 
+/// 回收 [`stack_alloc`] 分配的栈，`sstack` 为栈底的物理地址
+pub fn stack_dealloc(sstack: usize) {
+    FRAME_ALLOCATOR
+        .lock()
+        .dealloc(sstack / PAGE_SIZE, STACK_SIZE / PAGE_SIZE);
+}
+
 pub fn frame_alloc() -> Option<FrameTracker> {
     FRAME_ALLOCATOR
         .lock()
@@ -9,26 +9,37 @@ extern crate lazy_static;
 use core::arch::asm;
 
 mod address;
+mod error;
 mod frame_allocator;
 mod heap_allocator;
 mod memory_set;
 mod page_table;
+mod portal;
 
 use address::VPNRange;
 pub use address::{PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
-pub use frame_allocator::{frame_alloc, frame_dealloc, FrameTracker, stack_alloc};
+pub use error::{VmError, VmResult};
+pub use frame_allocator::{frame_alloc, frame_dealloc, FrameTracker, stack_alloc, stack_dealloc};
 use linker::locate_stack;
-pub use memory_set::{kernel_token, MapPermission, MemorySet, KERNEL_SPACE};
+pub use memory_set::{kernel_token, MapPermission, MemorySet, KERNEL_SPACES};
 use page_table::PTEFlags;
+pub use portal::{Portal, PortalPool, PORTAL_POOL};
 pub use page_table::{
     translated_byte_buffer, translated_ref, translated_refmut, translated_str, PageTable,
     PageTableEntry, UserBuffer, UserBufferIterator,
 };
 
-pub fn init() {
+/// 启动核初始化堆、物理页帧以及所有核的内核地址空间
+pub fn init(hartid: usize) {
     heap_allocator::init_heap();
     frame_allocator::init_frame_allocator();
-    KERNEL_SPACE.lock().activate();
+    init_hart(hartid);
+}
+
+/// 为第 `hartid` 个核分配异界传送门槽，并切换到这个核的内核地址空间
+pub fn init_hart(hartid: usize) {
+    PORTAL_POOL.lock().alloc(hartid).expect("portal of this hart is in use");
+    KERNEL_SPACES[hartid].lock().activate();
     // 将 sp 寄存器移动到高位虚拟地址，取消掉 stack 段的对等映射
     let mut sp: usize;
     unsafe { asm!("mv {sp}, sp", sp = out(reg) sp); }
@@ -39,5 +50,8 @@ pub fn init() {
             sp = in(reg) sp,         
         );
     }
-    KERNEL_SPACE.lock().remove_area_with_start_vpn(locate_stack().start.into());
+    KERNEL_SPACES[hartid]
+        .lock()
+        .remove_area_with_start_vpn(locate_stack().start.into())
+        .expect("boot stack is not mapped");
 }
@@ -2,7 +2,10 @@ use super::{frame_alloc, FrameTracker};
 use super::{PTEFlags, PageTable, PageTableEntry};
 use super::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use super::{StepByOne, VPNRange};
-use config::{MEMORY_END, PAGE_SIZE, TRAMPOLINE, STACK_START, STACK_SIZE};
+use super::Portal;
+use super::{VmError, VmResult};
+use super::address::is_canonical;
+use config::{MEMORY_END, PAGE_SIZE, STACK_START, STACK_SIZE, MAX_HART_NUM, VDSO_SIZE, MMAP_BASE, USER_SPACE_END, portal_va, vdso_va};
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
@@ -13,98 +16,167 @@ use spin::Mutex;
 use linker::*;
 
 lazy_static! {
-    pub static ref KERNEL_SPACE: Arc<Mutex<MemorySet>> =
-        Arc::new(Mutex::new(MemorySet::new_kernel()));
+    /// 每个核的内核地址空间，分别映射各自的内核栈和异界传送门
+    pub static ref KERNEL_SPACES: Vec<Arc<Mutex<MemorySet>>> = (0..MAX_HART_NUM)
+        .map(|hartid| {
+            let space = MemorySet::new_kernel(hartid).expect("failed to map kernel space");
+            Arc::new(Mutex::new(space))
+        })
+        .collect();
 }
 
-pub fn kernel_token() -> usize {
-    KERNEL_SPACE.lock().token()
+/// 第 `hartid` 个核的内核地址空间
+pub fn kernel_token(hartid: usize) -> usize {
+    KERNEL_SPACES[hartid].lock().token()
 }
 
 pub struct MemorySet {
     page_table: PageTable,
     areas: Vec<MapArea>,
+    /// 当前绑定的异界传送门槽
+    portal: Option<Portal>,
+    /// 已经映射的 vDSO 槽
+    vdso_mapped: [bool; MAX_HART_NUM],
+    /// 堆的起始位置，堆从这里向上增长
+    heap_bottom: usize,
+    /// 当前的程序断点，即堆的末尾
+    brk: usize,
 }
 
 impl MemorySet {
-    pub fn new_bare() -> Self {
-        Self {
-            page_table: PageTable::new(),
+    pub fn new_bare() -> VmResult<Self> {
+        Ok(Self {
+            page_table: PageTable::new()?,
             areas: Vec::new(),
-        }
+            portal: None,
+            vdso_mapped: [false; MAX_HART_NUM],
+            heap_bottom: 0,
+            brk: 0,
+        })
     }
     pub fn token(&self) -> usize {
         self.page_table.token()
     }
-    /// Assume that no conflicts.
+    /// 区域不能和已有的区域重叠
     pub fn insert_framed_area(
         &mut self,
         start_va: VirtAddr,
         end_va: VirtAddr,
         permission: MapPermission,
-    ) {
+    ) -> VmResult<()> {
         self.push(
             MapArea::new(start_va, end_va, MapType::Framed, permission),
             None,
-        );
+        )
+    }
+    /// 插入懒分配的区域，第一次访问时才分配物理页
+    pub fn insert_lazy_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) -> VmResult<()> {
+        self.push(
+            MapArea::new(start_va, end_va, MapType::Lazy, permission),
+            None,
+        )
     }
-    pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
-        if let Some((idx, area)) = self
+    pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) -> VmResult<()> {
+        let idx = self
             .areas
-            .iter_mut()
-            .enumerate()
-            .find(|(_, area)| area.vpn_range.get_start() == start_vpn)
-        {
-            area.unmap(&mut self.page_table);
-            self.areas.remove(idx);
-        }
+            .iter()
+            .position(|area| area.vpn_range.get_start() == start_vpn)
+            .ok_or(VmError::NotMapped)?;
+        let mut area = self.areas.remove(idx);
+        area.unmap(&mut self.page_table)
     }
-    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
-        map_area.map(&mut self.page_table);
+    /// 映射失败时已经映射的页被撤销，区域不会被加入
+    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) -> VmResult<()> {
+        if self.overlaps(map_area.vpn_range.get_start(), map_area.vpn_range.get_end()) {
+            return Err(VmError::Overlapped);
+        }
+        map_area.map(&mut self.page_table)?;
         if let Some(data) = data {
-            map_area.copy_data(&mut self.page_table, data);
+            if let Err(err) = map_area.copy_data(&mut self.page_table, data) {
+                map_area.unmap(&mut self.page_table)?;
+                return Err(err);
+            }
         }
         self.areas.push(map_area);
+        Ok(())
     }
+    /// 在 `va` 处映射异界传送门
+    ///
     /// Mention that trampoline is not collected by areas.
-    pub fn map_trampoline(&mut self) {
-        let strampoline = locate_trampoline().start;
+    fn map_portal(&mut self, va: usize) -> VmResult<()> {
+        let trampoline = locate_trampoline();
+        assert!(trampoline.end - trampoline.start <= PAGE_SIZE, "trampoline is too large");
+        let strampoline = trampoline.start;
         self.page_table.map(
-            VirtAddr::from(TRAMPOLINE).into(),
+            VirtAddr::from(va).into(),
             PhysAddr::from(strampoline).into(),
             PTEFlags::R | PTEFlags::X,
-        );
+        )
     }
-    /// 映射栈
-    pub fn map_stack(&mut self, sstack: usize) {
+    /// 把栈映射到高位地址，栈中保存着陷入的上下文，`perm` 不能带上 U 标志
+    pub fn map_stack(&mut self, sstack: usize, perm: MapPermission) -> VmResult<()> {
+        let flags = PTEFlags::from_bits(perm.bits).unwrap();
         for i in 0..(STACK_SIZE / PAGE_SIZE) {
             // println!("{:#x}-{:#x}", STACK_START + i * PAGE_SIZE, sstack + i * PAGE_SIZE);
             self.page_table.map(
                 VirtAddr::from(STACK_START + i * PAGE_SIZE).into(),
                 PhysAddr::from(sstack + i * PAGE_SIZE).into(),
-                PTEFlags::R | PTEFlags::W,
-            );
+                flags,
+            )?;
         }
+        Ok(())
     }
-    /// 映射 vdso 段
-    pub fn map_vdso(&mut self) {
-        let vdso_para= locate_vdso();
-        self.push(
-            MapArea::new(
-                (vdso_para.start).into(),
-                (vdso_para.end).into(),
-                MapType::Identical,
-                MapPermission::R | MapPermission::X | MapPermission::U,
-            ),
-            None,
-        );
-    }
-    /// Without kernel stacks.
-    pub fn new_kernel() -> Self {
-        let mut memory_set = Self::new_bare();
-        // map trampoline
-        memory_set.map_trampoline();
-        memory_set.map_stack(locate_stack().start);
+    /// 在 `va` 处映射 vdso 段，同样不记录在 areas 中
+    fn map_vdso(&mut self, va: usize) -> VmResult<()> {
+        let vdso_para = locate_vdso();
+        assert!(vdso_para.end - vdso_para.start <= VDSO_SIZE, "vdso is too large");
+        for pa in (vdso_para.start..vdso_para.end).step_by(PAGE_SIZE) {
+            self.page_table.map(
+                VirtAddr::from(va + pa - vdso_para.start).into(),
+                PhysAddr::from(pa).into(),
+                PTEFlags::R | PTEFlags::X | PTEFlags::U,
+            )?;
+        }
+        Ok(())
+    }
+    /// 绑定或者重新绑定到某个核的异界传送门槽，返回之前绑定的槽
+    ///
+    /// 之前的异界传送门会被取消映射，但是 vDSO 槽会一直保留，
+    /// 因为用户态可能还保存着旧的 vDSO 槽中的返回地址
+    pub fn bind_portal(&mut self, portal: Portal) -> VmResult<Option<Portal>> {
+        let old = self.portal;
+        if old == Some(portal) {
+            return Ok(old);
+        }
+        if let Some(old) = old {
+            self.page_table.unmap(VirtAddr::from(old.portal_va()).into())?;
+            self.portal = None;
+        }
+        self.map_portal(portal.portal_va())?;
+        self.portal = Some(portal);
+        if !self.vdso_mapped[portal.hartid()] {
+            self.map_vdso(portal.vdso_va())?;
+            self.vdso_mapped[portal.hartid()] = true;
+        }
+        Ok(old)
+    }
+    /// 当前绑定的异界传送门槽
+    pub fn portal(&self) -> Option<Portal> {
+        self.portal
+    }
+    /// 第 `hartid` 个核的内核地址空间，高位地址映射这个核的内核栈
+    pub fn new_kernel(hartid: usize) -> VmResult<Self> {
+        let mut memory_set = Self::new_bare()?;
+        memory_set.map_portal(portal_va(hartid))?;
+        memory_set.map_stack(
+            locate_stack().start + hartid * STACK_SIZE,
+            MapPermission::R | MapPermission::W,
+        )?;
         // map kernel sections
         let text_para = locate_text();
         log::info!("mapping .text section {:#x?}", text_para);
@@ -116,7 +188,7 @@ impl MemorySet {
                 MapPermission::R | MapPermission::X,
             ),
             None,
-        );
+        )?;
         let rodata_para = locate_rodata();
         log::info!("mapping .rodata section {:#x?}", rodata_para);
         memory_set.push(
@@ -127,7 +199,7 @@ impl MemorySet {
                 MapPermission::R,
             ),
             None,
-        );
+        )?;
         let data_para = locate_data();
         log::info!("mapping .data section {:#x?}", data_para);
         memory_set.push(
@@ -138,7 +210,8 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )?;
+        // 启动时使用的栈，切换到高位地址之后取消映射
         let stack_para = locate_stack();
         log::info!("mapping stack {:#x?}", stack_para);
         memory_set.push(
@@ -149,7 +222,7 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )?;
         let bss_para = locate_bss();
         log::info!("mapping .bss section {:#x?}", bss_para);
         memory_set.push(
@@ -160,7 +233,7 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )?;
         log::info!("mapping physical memory");
         memory_set.push(
             MapArea::new(
@@ -170,25 +243,28 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
-        memory_set
+        )?;
+        Ok(memory_set)
     }
     /// Include sections in elf and trampoline,
     /// also returns user_sp_base and entry point.
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
-        let mut memory_set = Self::new_bare();
-        // map trampoline
-        memory_set.map_trampoline();
+    ///
+    /// ELF 文件不合法或者段的数据超出文件时返回 [`VmError::BadElf`]
+    pub fn from_elf(elf_data: &[u8]) -> VmResult<(Self, usize, usize)> {
         // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
+        let elf = xmas_elf::ElfFile::new(elf_data).map_err(|_| VmError::BadElf)?;
         let elf_header = elf.header;
         let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
+        if magic != [0x7f, 0x45, 0x4c, 0x46] {
+            return Err(VmError::BadElf);
+        }
+        // portal and vdso are bound before running
+        let mut memory_set = Self::new_bare()?;
         let ph_count = elf_header.pt2.ph_count();
         let mut max_end_vpn = VirtPageNum(0);
         for i in 0..ph_count {
-            let ph = elf.program_header(i).unwrap();
-            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
+            let ph = elf.program_header(i).map_err(|_| VmError::BadElf)?;
+            if ph.get_type().map_err(|_| VmError::BadElf)? == xmas_elf::program::Type::Load {
                 let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
                 let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
                 let mut map_perm = MapPermission::U;
@@ -202,41 +278,56 @@ impl MemorySet {
                 if ph_flags.is_execute() {
                     map_perm |= MapPermission::X;
                 }
+                let data = (ph.offset() as usize)
+                    .checked_add(ph.file_size() as usize)
+                    .and_then(|end| elf.input.get(ph.offset() as usize..end))
+                    .filter(|data| data.len() <= ph.mem_size() as usize)
+                    .ok_or(VmError::BadElf)?;
                 let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
                 max_end_vpn = map_area.vpn_range.get_end();
-                memory_set.push(
-                    map_area,
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
+                memory_set.push(map_area, Some(data))?;
             }
         }
         let max_end_va: VirtAddr = max_end_vpn.into();
         let mut user_stack_base: usize = max_end_va.into();
         user_stack_base += PAGE_SIZE;
-        (
+        Ok((
             memory_set,
             user_stack_base,
             elf.header.pt2.entry_point() as usize,
-        )
+        ))
     }
-    pub fn from_existed_user(user_space: &MemorySet) -> MemorySet {
-        let mut memory_set = Self::new_bare();
-        // map trampoline
-        memory_set.map_trampoline();
-        // copy data sections/trap_context/user_stack
+    /// 写时复制地复制用户地址空间
+    ///
+    /// 两个地址空间只读地共享已经分配的物理页，写入时再复制，见 [`MemorySet::handle_page_fault`]。
+    /// 同时映射原地址空间已经映射的 vDSO 槽，因为用户态可能保存着其中的返回地址；
+    /// 异界传送门在运行之前绑定，进程的栈由调用者重新分配和映射
+    pub fn from_existed_user(user_space: &mut MemorySet) -> VmResult<MemorySet> {
+        let mut memory_set = Self::new_bare()?;
         for area in user_space.areas.iter() {
-            let new_area = MapArea::from_another(area);
-            memory_set.push(new_area, None);
-            // copy data from another space
-            for vpn in area.vpn_range {
-                let src_ppn = user_space.translate(vpn).unwrap().ppn();
-                let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
-                dst_ppn
-                    .get_bytes_array()
-                    .copy_from_slice(src_ppn.get_bytes_array());
+            let mut new_area = MapArea::from_another(area);
+            let flags = PTEFlags::from_bits(area.map_perm.bits).unwrap() - PTEFlags::W;
+            // lazy areas only share allocated pages
+            for (&vpn, frame) in area.data_frames.iter() {
+                user_space.page_table.remap(vpn, frame.ppn, flags)?;
+                // 先加入区域，失败时页表项和引用计数一致
+                new_area.data_frames.insert(vpn, frame.clone());
+                if let Err(err) = memory_set.page_table.map(vpn, frame.ppn, flags) {
+                    new_area.data_frames.remove(&vpn);
+                    return Err(err);
+                }
             }
+            memory_set.areas.push(new_area);
         }
-        memory_set
+        for hartid in 0..MAX_HART_NUM {
+            if user_space.vdso_mapped[hartid] {
+                memory_set.map_vdso(vdso_va(hartid))?;
+                memory_set.vdso_mapped[hartid] = true;
+            }
+        }
+        memory_set.heap_bottom = user_space.heap_bottom;
+        memory_set.brk = user_space.brk;
+        Ok(memory_set)
     }
     pub fn activate(&self) {
         let satp = self.page_table.token();
@@ -248,6 +339,200 @@ impl MemorySet {
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
     }
+    /// 处理用户态访问 `va` 触发的缺页异常，`access` 为访问需要的权限
+    ///
+    /// 区域的权限允许这次访问时，为懒分配的页映射清零的物理页；
+    /// 页已经映射时不需要处理，内核也用它提前分配用户内存。
+    /// 不属于任何区域或者权限不足时是段错误
+    pub fn handle_page_fault(&mut self, va: usize, access: MapPermission) -> VmResult<()> {
+        let vpn = VirtAddr::from(va).floor();
+        let area = self
+            .areas
+            .iter_mut()
+            .find(|area| area.contains(vpn))
+            .ok_or(VmError::BadAddress)?;
+        if !area.map_perm.contains(access | MapPermission::U) {
+            return Err(VmError::PermissionDenied);
+        }
+        if let Some(pte) = self.page_table.translate(vpn) {
+            // 写入写时复制的页
+            if access.contains(MapPermission::W) && !pte.writable() {
+                area.copy_on_write(&mut self.page_table, vpn)?;
+            }
+            return Ok(());
+        }
+        if area.map_type != MapType::Lazy {
+            return Err(VmError::NotMapped);
+        }
+        area.map_one(&mut self.page_table, vpn)
+    }
+    /// 提前分配 `start` 开始的 `len` 字节用户内存，内核访问用户传入的缓冲区之前调用
+    ///
+    /// 每一页都要属于某个区域并且允许 `access` 访问，需要写入的写时复制的页同时被复制。
+    /// 线程栈、执行器等区域位于 [`USER_SPACE_END`] 之上，由区域检查地址
+    pub fn fault_in(&mut self, start: usize, len: usize, access: MapPermission) -> VmResult<()> {
+        let end = start.checked_add(len).ok_or(VmError::InvalidArgument)?;
+        if !is_canonical(start, end) {
+            return Err(VmError::BadAddress);
+        }
+        let mut va = start & !(PAGE_SIZE - 1);
+        while va < end {
+            self.handle_page_fault(va, access)?;
+            match va.checked_add(PAGE_SIZE) {
+                Some(next) => va = next,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+    /// `start..end` 中是否有页属于已有的区域
+    fn overlaps(&self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        self.areas.iter().any(|area| area.overlaps(start, end))
+    }
+    /// 如果有区域跨过 `vpn`，从 `vpn` 处把它分成两个区域
+    fn split_at(&mut self, vpn: VirtPageNum) {
+        if let Some(area) = self
+            .areas
+            .iter_mut()
+            .find(|area| area.vpn_range.get_start() < vpn && vpn < area.vpn_range.get_end())
+        {
+            let tail = area.split_off(vpn);
+            self.areas.push(tail);
+        }
+    }
+    /// 合并首尾相接并且类型和权限都相同的区域
+    fn merge_areas(&mut self) {
+        self.areas.sort_by_key(|area| area.vpn_range.get_start());
+        let mut merged: Vec<MapArea> = Vec::with_capacity(self.areas.len());
+        for area in self.areas.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.can_merge(&area) => last.merge(area),
+                _ => merged.push(area),
+            }
+        }
+        self.areas = merged;
+    }
+    /// 检查用户传入的范围，返回对应的页号范围
+    ///
+    /// 起始地址需要按页对齐，长度不能为 0，范围不能超出 [`USER_SPACE_END`]
+    fn user_range(start: usize, len: usize) -> VmResult<(VirtPageNum, VirtPageNum)> {
+        let end = start.checked_add(len).ok_or(VmError::InvalidArgument)?;
+        if start % PAGE_SIZE != 0 || len == 0 || end > USER_SPACE_END {
+            return Err(VmError::InvalidArgument);
+        }
+        Ok((VirtAddr::from(start).floor(), VirtAddr::from(end).ceil()))
+    }
+    /// 映射 `len` 字节的匿名内存，物理页在访问时才分配，返回映射的起始地址
+    ///
+    /// `start` 为 0 时从 [`MMAP_BASE`] 开始查找空闲的地址，否则这段地址不能已经被映射
+    pub fn mmap(&mut self, start: usize, len: usize, perm: MapPermission) -> VmResult<usize> {
+        let start = if start == 0 {
+            let pages = len / PAGE_SIZE + (len % PAGE_SIZE != 0) as usize;
+            let mut candidate = VirtAddr::from(MMAP_BASE).floor();
+            // 候选范围和某个区域重叠时，从这个区域的末尾继续查找
+            while let Some(area) = self
+                .areas
+                .iter()
+                .find(|area| area.overlaps(candidate, VirtPageNum(candidate.0 + pages)))
+            {
+                candidate = area.vpn_range.get_end();
+            }
+            VirtAddr::from(candidate).0
+        } else {
+            start
+        };
+        let (start_vpn, end_vpn) = Self::user_range(start, len)?;
+        self.push(
+            MapArea::new(start_vpn.into(), end_vpn.into(), MapType::Lazy, perm | MapPermission::U),
+            None,
+        )?;
+        self.merge_areas();
+        Ok(start)
+    }
+    /// 取消映射 `start` 开始的 `len` 字节，可以是区域的一部分，其中没有映射的页被忽略
+    pub fn munmap(&mut self, start: usize, len: usize) -> VmResult<()> {
+        let (start_vpn, end_vpn) = Self::user_range(start, len)?;
+        self.unmap_range(start_vpn, end_vpn)
+    }
+    /// 取消映射 `start..end` 中的页，跨过边界的区域被分开
+    fn unmap_range(&mut self, start: VirtPageNum, end: VirtPageNum) -> VmResult<()> {
+        self.split_at(start);
+        self.split_at(end);
+        let (removed, kept): (Vec<MapArea>, Vec<MapArea>) = self.areas.drain(..).partition(|area| area.overlaps(start, end));
+        self.areas = kept;
+        for mut area in removed {
+            area.unmap(&mut self.page_table)?;
+        }
+        Ok(())
+    }
+    /// 修改 `start` 开始的 `len` 字节的权限，可以是区域的一部分，其中每一页都需要已经映射
+    ///
+    /// 已经分配的页同步修改页表，仍然写时复制的页保持只读
+    pub fn mprotect(&mut self, start: usize, len: usize, perm: MapPermission) -> VmResult<()> {
+        let (start_vpn, end_vpn) = Self::user_range(start, len)?;
+        let covered: usize = self
+            .areas
+            .iter()
+            .map(|area| area.overlap_len(start_vpn, end_vpn))
+            .sum();
+        if covered != end_vpn.0 - start_vpn.0 {
+            return Err(VmError::NotMapped);
+        }
+        self.split_at(start_vpn);
+        self.split_at(end_vpn);
+        for area in self.areas.iter_mut() {
+            if area.overlaps(start_vpn, end_vpn) {
+                area.set_permission(&mut self.page_table, perm | MapPermission::U)?;
+            }
+        }
+        self.merge_areas();
+        Ok(())
+    }
+    /// 设置堆的起始位置，此时堆为空
+    pub fn init_brk(&mut self, heap_bottom: usize) {
+        self.heap_bottom = heap_bottom;
+        self.brk = heap_bottom;
+    }
+    /// 当前的程序断点
+    pub fn current_brk(&self) -> usize {
+        self.brk
+    }
+    /// 把程序断点移动到 `new_brk`，返回新的程序断点
+    ///
+    /// 增长的部分是懒分配的，不能和其他区域重叠；缩小时回收多余的页。
+    /// 程序断点不能低于堆的起始位置
+    pub fn brk(&mut self, new_brk: usize) -> VmResult<usize> {
+        if new_brk < self.heap_bottom || new_brk > USER_SPACE_END {
+            return Err(VmError::InvalidArgument);
+        }
+        let old_end = VirtAddr::from(self.brk).ceil();
+        let new_end = VirtAddr::from(new_brk).ceil();
+        if new_end > old_end {
+            self.push(
+                MapArea::new(
+                    old_end.into(),
+                    new_end.into(),
+                    MapType::Lazy,
+                    MapPermission::R | MapPermission::W | MapPermission::U,
+                ),
+                None,
+            )?;
+            self.merge_areas();
+        } else if new_end < old_end {
+            self.unmap_range(new_end, old_end)?;
+        }
+        self.brk = new_brk;
+        Ok(new_brk)
+    }
+    /// 把程序断点移动 `increment` 字节，返回原来的程序断点
+    pub fn sbrk(&mut self, increment: isize) -> VmResult<usize> {
+        let old_brk = self.brk;
+        let new_brk = old_brk
+            .checked_add_signed(increment)
+            .ok_or(VmError::InvalidArgument)?;
+        self.brk(new_brk)?;
+        Ok(old_brk)
+    }
     pub fn recycle_data_pages(&mut self) {
         //*self = Self::new_bare();
         self.areas.clear();
@@ -256,7 +541,8 @@ impl MemorySet {
 
 pub struct MapArea {
     vpn_range: VPNRange,
-    data_frames: BTreeMap<VirtPageNum, FrameTracker>,
+    /// 写时复制的物理页被多个地址空间共享
+    data_frames: BTreeMap<VirtPageNum, Arc<FrameTracker>>,
     map_type: MapType,
     map_perm: MapPermission,
 }
@@ -285,40 +571,119 @@ impl MapArea {
             map_perm: another.map_perm,
         }
     }
-    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
-        let ppn: PhysPageNum;
+    /// 和 `start..end` 重叠的页数
+    fn overlap_len(&self, start: VirtPageNum, end: VirtPageNum) -> usize {
+        let start = start.max(self.vpn_range.get_start());
+        let end = end.min(self.vpn_range.get_end());
+        end.0.saturating_sub(start.0)
+    }
+    /// 是否和 `start..end` 重叠
+    fn overlaps(&self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        self.overlap_len(start, end) > 0
+    }
+    /// 从 `vpn` 处分成两个区域，返回后一半
+    fn split_off(&mut self, vpn: VirtPageNum) -> MapArea {
+        let tail = Self {
+            vpn_range: VPNRange::new(vpn, self.vpn_range.get_end()),
+            data_frames: self.data_frames.split_off(&vpn),
+            map_type: self.map_type,
+            map_perm: self.map_perm,
+        };
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), vpn);
+        tail
+    }
+    /// `next` 紧接在这个区域之后，并且类型和权限都相同
+    fn can_merge(&self, next: &MapArea) -> bool {
+        self.vpn_range.get_end() == next.vpn_range.get_start()
+            && self.map_type == next.map_type
+            && self.map_type != MapType::Identical
+            && self.map_perm == next.map_perm
+    }
+    fn merge(&mut self, mut next: MapArea) {
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), next.vpn_range.get_end());
+        self.data_frames.append(&mut next.data_frames);
+    }
+    /// 修改区域的权限，同步修改已经分配的页的页表项
+    fn set_permission(&mut self, page_table: &mut PageTable, perm: MapPermission) -> VmResult<()> {
+        self.map_perm = perm;
+        let flags = PTEFlags::from_bits(perm.bits).unwrap();
+        for (&vpn, frame) in self.data_frames.iter() {
+            // 还被其他地址空间共享的页写入时仍然需要复制
+            let flags = if Arc::strong_count(frame) > 1 { flags - PTEFlags::W } else { flags };
+            page_table.remap(vpn, frame.ppn, flags)?;
+        }
+        Ok(())
+    }
+    /// 是否包含 `vpn` 这一页
+    pub fn contains(&self, vpn: VirtPageNum) -> bool {
+        self.vpn_range.get_start() <= vpn && vpn < self.vpn_range.get_end()
+    }
+    /// 映射失败时分配的物理页被回收
+    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> VmResult<()> {
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
         match self.map_type {
-            MapType::Identical => {
-                ppn = PhysPageNum(vpn.0);
+            MapType::Identical => page_table.map(vpn, PhysPageNum(vpn.0), pte_flags),
+            MapType::Framed | MapType::Lazy => {
+                let frame = frame_alloc().ok_or(VmError::OutOfMemory)?;
+                page_table.map(vpn, frame.ppn, pte_flags)?;
+                self.data_frames.insert(vpn, Arc::new(frame));
+                Ok(())
             }
+        }
+    }
+    pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> VmResult<()> {
+        match self.map_type {
+            MapType::Identical => {}
             MapType::Framed => {
-                let frame = frame_alloc().unwrap();
-                ppn = frame.ppn;
-                self.data_frames.insert(vpn, frame);
+                self.data_frames.remove(&vpn);
+            }
+            // 还没有访问过的页没有映射
+            MapType::Lazy => {
+                if self.data_frames.remove(&vpn).is_none() {
+                    return Ok(());
+                }
             }
         }
-        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
-        page_table.map(vpn, ppn, pte_flags);
+        page_table.unmap(vpn)
     }
-    pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
-        if self.map_type == MapType::Framed {
-            self.data_frames.remove(&vpn);
+    /// 写入共享的物理页时复制一份，只剩这个区域引用时直接恢复写权限
+    fn copy_on_write(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> VmResult<()> {
+        let frame = self.data_frames.get_mut(&vpn).ok_or(VmError::NotMapped)?;
+        if Arc::strong_count(frame) > 1 {
+            let copied = frame_alloc().ok_or(VmError::OutOfMemory)?;
+            copied
+                .ppn
+                .get_bytes_array()
+                .copy_from_slice(frame.ppn.get_bytes_array());
+            *frame = Arc::new(copied);
         }
-        page_table.unmap(vpn);
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+        page_table.remap(vpn, frame.ppn, pte_flags)
     }
-    pub fn map(&mut self, page_table: &mut PageTable) {
+    /// 懒分配的区域在缺页时才映射，映射失败时撤销已经映射的页
+    pub fn map(&mut self, page_table: &mut PageTable) -> VmResult<()> {
+        if self.map_type == MapType::Lazy {
+            return Ok(());
+        }
         for vpn in self.vpn_range {
-            self.map_one(page_table, vpn);
+            if let Err(err) = self.map_one(page_table, vpn) {
+                for mapped in VPNRange::new(self.vpn_range.get_start(), vpn) {
+                    self.unmap_one(page_table, mapped)?;
+                }
+                return Err(err);
+            }
         }
+        Ok(())
     }
-    pub fn unmap(&mut self, page_table: &mut PageTable) {
+    pub fn unmap(&mut self, page_table: &mut PageTable) -> VmResult<()> {
         for vpn in self.vpn_range {
-            self.unmap_one(page_table, vpn);
+            self.unmap_one(page_table, vpn)?;
         }
+        Ok(())
     }
     /// data: start-aligned but maybe with shorter length
     /// assume that all frames were cleared before
-    pub fn copy_data(&mut self, page_table: &mut PageTable, data: &[u8]) {
+    pub fn copy_data(&mut self, page_table: &mut PageTable, data: &[u8]) -> VmResult<()> {
         assert_eq!(self.map_type, MapType::Framed);
         let mut start: usize = 0;
         let mut current_vpn = self.vpn_range.get_start();
@@ -327,7 +692,7 @@ impl MapArea {
             let src = &data[start..len.min(start + PAGE_SIZE)];
             let dst = &mut page_table
                 .translate(current_vpn)
-                .unwrap()
+                .ok_or(VmError::NotMapped)?
                 .ppn()
                 .get_bytes_array()[..src.len()];
             dst.copy_from_slice(src);
@@ -337,6 +702,7 @@ impl MapArea {
             }
             current_vpn.step();
         }
+        Ok(())
     }
 }
 
@@ -344,6 +710,8 @@ impl MapArea {
 pub enum MapType {
     Identical,
     Framed,
+    /// 第一次访问时才分配物理页
+    Lazy,
 }
 
 bitflags! {
@@ -1,8 +1,10 @@
-use super::{frame_alloc, FrameTracker, PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
+use super::address::is_canonical;
+use super::{frame_alloc, FrameTracker, VmError, VmResult, PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use bitflags::*;
+use config::PAGE_SIZE;
 
 bitflags! {
     pub struct PTEFlags: u8 {
@@ -57,14 +59,14 @@ pub struct PageTable {
     frames: Vec<FrameTracker>,
 }
 
-/// Assume that it won't oom when creating/mapping.
+/// 分配页表页失败时返回 [`VmError::OutOfMemory`]
 impl PageTable {
-    pub fn new() -> Self {
-        let frame = frame_alloc().unwrap();
-        PageTable {
+    pub fn new() -> VmResult<Self> {
+        let frame = frame_alloc().ok_or(VmError::OutOfMemory)?;
+        Ok(PageTable {
             root_ppn: frame.ppn,
             frames: vec![frame],
-        }
+        })
     }
     /// Temporarily used to get arguments from user space.
     pub fn from_token(satp: usize) -> Self {
@@ -73,24 +75,19 @@ impl PageTable {
             frames: Vec::new(),
         }
     }
-    fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    fn find_pte_create(&mut self, vpn: VirtPageNum) -> VmResult<&mut PageTableEntry> {
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
-        let mut result: Option<&mut PageTableEntry> = None;
-        for (i, idx) in idxs.iter().enumerate() {
+        for idx in &idxs[..2] {
             let pte = &mut ppn.get_pte_array()[*idx];
-            if i == 2 {
-                result = Some(pte);
-                break;
-            }
             if !pte.is_valid() {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc().ok_or(VmError::OutOfMemory)?;
                 *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
                 self.frames.push(frame);
             }
             ppn = pte.ppn();
         }
-        result
+        Ok(&mut ppn.get_pte_array()[idxs[2]])
     }
     fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
         let idxs = vpn.indexes();
@@ -109,23 +106,37 @@ impl PageTable {
         }
         result
     }
+    /// 找到已经映射的页的页表项
+    fn find_valid_pte(&self, vpn: VirtPageNum) -> VmResult<&mut PageTableEntry> {
+        self.find_pte(vpn)
+            .filter(|pte| pte.is_valid())
+            .ok_or(VmError::NotMapped)
+    }
     #[allow(unused)]
-    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        let pte = self.find_pte_create(vpn).unwrap();
-        assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
+    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> VmResult<()> {
+        let pte = self.find_pte_create(vpn)?;
+        if pte.is_valid() {
+            return Err(VmError::AlreadyMapped);
+        }
         *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        Ok(())
     }
     #[allow(unused)]
-    pub fn unmap(&mut self, vpn: VirtPageNum) {
-        let pte = self.find_pte(vpn).unwrap();
-        assert!(pte.is_valid(), "vpn {:?} is invalid before unmapping", vpn);
-        *pte = PageTableEntry::empty();
+    pub fn unmap(&mut self, vpn: VirtPageNum) -> VmResult<()> {
+        *self.find_valid_pte(vpn)? = PageTableEntry::empty();
+        Ok(())
+    }
+    /// 修改已经映射的页的物理页和标志位
+    pub fn remap(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> VmResult<()> {
+        *self.find_valid_pte(vpn)? = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        Ok(())
     }
+    /// 没有映射的页，包括还没有访问过的懒分配的页，返回 `None`
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-        self.find_pte(vpn).map(|pte| *pte)
+        self.find_valid_pte(vpn).ok().map(|pte| *pte)
     }
     pub fn translate_va(&self, va: VirtAddr) -> Option<PhysAddr> {
-        self.find_pte(va.clone().floor()).map(|pte| {
+        self.find_valid_pte(va.clone().floor()).ok().map(|pte| {
             let aligned_pa: PhysAddr = pte.ppn().into();
             let offset = va.page_offset();
             let aligned_pa_usize: usize = aligned_pa.into();
@@ -137,62 +148,81 @@ impl PageTable {
     }
 }
 
-pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&'static mut [u8]> {
+/// 找到用户态可以访问的页的页表项，内核通过它访问用户传入的指针
+fn translate_user(page_table: &PageTable, vpn: VirtPageNum) -> VmResult<PageTableEntry> {
+    let pte = page_table.translate(vpn).ok_or(VmError::NotMapped)?;
+    if !pte.flags().contains(PTEFlags::U) {
+        return Err(VmError::PermissionDenied);
+    }
+    Ok(pte)
+}
+
+/// 地址范围溢出或者页没有映射时返回错误，调用之前用 [`MemorySet::fault_in`] 分配懒分配的页
+///
+/// [`MemorySet::fault_in`]: crate::MemorySet::fault_in
+pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> VmResult<Vec<&'static mut [u8]>> {
     let page_table = PageTable::from_token(token);
     let mut start = ptr as usize;
-    let end = start + len;
+    let end = start.checked_add(len).ok_or(VmError::InvalidArgument)?;
+    if !is_canonical(start, end) {
+        return Err(VmError::InvalidArgument);
+    }
     let mut v = Vec::new();
+    // 用截断之前的地址比较，高地址截断之后永远小于 `end`
     while start < end {
         let start_va = VirtAddr::from(start);
-        let mut vpn = start_va.floor();
-        let ppn = page_table.translate(vpn).unwrap().ppn();
-        vpn.step();
-        let mut end_va: VirtAddr = vpn.into();
-        end_va = end_va.min(VirtAddr::from(end));
-        if end_va.page_offset() == 0 {
-            v.push(&mut ppn.get_bytes_array()[start_va.page_offset()..]);
-        } else {
-            v.push(&mut ppn.get_bytes_array()[start_va.page_offset()..end_va.page_offset()]);
-        }
-        start = end_va.into();
+        let ppn = translate_user(&page_table, start_va.floor())?.ppn();
+        let page_end = (start | (PAGE_SIZE - 1)).saturating_add(1).min(end);
+        let offset = start_va.page_offset();
+        v.push(&mut ppn.get_bytes_array()[offset..offset + (page_end - start)]);
+        start = page_end;
     }
-    v
+    Ok(v)
 }
 
 /// Load a string from other address spaces into kernel space without an end `\0`.
-pub fn translated_str(token: usize, ptr: *const u8) -> String {
+pub fn translated_str(token: usize, ptr: *const u8) -> VmResult<String> {
     let page_table = PageTable::from_token(token);
     let mut string = String::new();
     let mut va = ptr as usize;
     loop {
-        let ch: u8 = *(page_table
-            .translate_va(VirtAddr::from(va))
-            .unwrap()
-            .get_mut());
+        let ch: u8 = *translated_user_va(&page_table, va, 1)?.get_mut();
         if ch == 0 {
             break;
         }
         string.push(ch as char);
-        va += 1;
+        va = va.checked_add(1).ok_or(VmError::InvalidArgument)?;
     }
-    string
+    Ok(string)
 }
 
-pub fn translated_ref<T>(token: usize, ptr: *const T) -> &'static T {
+/// 把用户态 `va` 处的 `size` 字节翻译成物理地址，这段内存不能跨页
+fn translated_user_va(page_table: &PageTable, va: usize, size: usize) -> VmResult<PhysAddr> {
+    if !is_canonical(va, va.saturating_add(size)) {
+        return Err(VmError::InvalidArgument);
+    }
+    let va = VirtAddr::from(va);
+    if va.page_offset() + size > PAGE_SIZE {
+        return Err(VmError::InvalidArgument);
+    }
+    let aligned_pa: PhysAddr = translate_user(page_table, va.floor())?.ppn().into();
+    Ok((usize::from(aligned_pa) + va.page_offset()).into())
+}
+
+pub fn translated_ref<T>(token: usize, ptr: *const T) -> VmResult<&'static T> {
     let page_table = PageTable::from_token(token);
-    page_table
-        .translate_va(VirtAddr::from(ptr as usize))
-        .unwrap()
-        .get_ref()
+    if ptr as usize % core::mem::align_of::<T>() != 0 {
+        return Err(VmError::InvalidArgument);
+    }
+    Ok(translated_user_va(&page_table, ptr as usize, core::mem::size_of::<T>())?.get_ref())
 }
 
-pub fn translated_refmut<T>(token: usize, ptr: *mut T) -> &'static mut T {
+pub fn translated_refmut<T>(token: usize, ptr: *mut T) -> VmResult<&'static mut T> {
     let page_table = PageTable::from_token(token);
-    let va = ptr as usize;
-    page_table
-        .translate_va(VirtAddr::from(va))
-        .unwrap()
-        .get_mut()
+    if ptr as usize % core::mem::align_of::<T>() != 0 {
+        return Err(VmError::InvalidArgument);
+    }
+    Ok(translated_user_va(&page_table, ptr as usize, core::mem::size_of::<T>())?.get_mut())
 }
 
 pub struct UserBuffer {
@@ -0,0 +1,58 @@
+use config::{MAX_HART_NUM, portal_va, vdso_va};
+use spin::Mutex;
+
+/// 异界传送门槽
+///
+/// 每个核独占一个槽，包括这个核的异界传送门以及 vDSO 槽，
+/// 进程在某个核上运行之前需要绑定这个核的槽
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Portal {
+    hartid: usize,
+}
+
+impl Portal {
+    /// 槽所属的核
+    pub fn hartid(&self) -> usize {
+        self.hartid
+    }
+    /// 异界传送门的虚拟地址
+    pub fn portal_va(&self) -> usize {
+        portal_va(self.hartid)
+    }
+    /// vDSO 槽的虚拟地址
+    pub fn vdso_va(&self) -> usize {
+        vdso_va(self.hartid)
+    }
+}
+
+/// 异界传送门池，记录每个核的槽是否已经分配出去
+pub struct PortalPool {
+    allocated: [bool; MAX_HART_NUM],
+}
+
+impl PortalPool {
+    const fn new() -> Self {
+        Self { allocated: [false; MAX_HART_NUM] }
+    }
+    /// 为第 `hartid` 个核分配槽，每个槽同时只能分配给一个核
+    pub fn alloc(&mut self, hartid: usize) -> Option<Portal> {
+        let allocated = self.allocated.get_mut(hartid)?;
+        if *allocated {
+            return None;
+        }
+        *allocated = true;
+        Some(Portal { hartid })
+    }
+    /// 回收槽，核停止运行之后才能回收
+    pub fn dealloc(&mut self, portal: Portal) {
+        assert!(self.allocated[portal.hartid], "portal {:?} is not allocated", portal);
+        self.allocated[portal.hartid] = false;
+    }
+    /// 已经分配给第 `hartid` 个核的槽
+    pub fn get(&self, hartid: usize) -> Option<Portal> {
+        self.allocated.get(hartid).filter(|&&allocated| allocated).map(|_| Portal { hartid })
+    }
+}
+
+/// 全局的异界传送门池
+pub static PORTAL_POOL: Mutex<PortalPool> = Mutex::new(PortalPool::new());
@@ -51,6 +51,9 @@ struct BuildArgs {
     /// features
     #[clap(long)]
     log: Option<String>,
+    /// Path of the user ELF to load as the first process.
+    #[clap(long)]
+    app: Option<String>,
     /// Build in debug mode.
     #[clap(long)]
     release: bool,
@@ -72,6 +75,10 @@ impl BuildArgs {
             .optional(&self.log, |cargo, log| {
                 cargo.env("LOG", log);
             })
+            .optional(&self.app, |cargo, app| {
+                // 内核的 build.rs 在另外的目录下引用这个文件
+                cargo.env("APP", std::fs::canonicalize(app).unwrap());
+            })
             .conditional(self.release, |cargo| {
                 cargo.release();
             })